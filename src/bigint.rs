@@ -0,0 +1,241 @@
+//! Arbitrary-precision integers that arithmetic promotes to on overflow.
+//!
+//! Only what the arithmetic builtins need: conversion from and back to
+//! `isize`, negation, addition, multiplication, division, ordering and
+//! decimal printing and parsing.
+
+use std::cmp::Ordering;
+
+/// Each limb holds nine decimal digits, which keeps printing simple.
+const BASE: u64 = 1_000_000_000;
+
+/// A signed integer of any size. Zero is never negative.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct BigInt {
+    negative: bool,
+    limbs: Vec<u32>,
+}
+
+fn trim(limbs: &mut Vec<u32>) {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+}
+
+fn cmp_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut limbs = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        limbs.push((sum % BASE) as u32);
+        carry = sum / BASE;
+    }
+    if carry > 0 {
+        limbs.push(carry as u32);
+    }
+    limbs
+}
+
+/// `a - b`, where `a` is at least as large as `b`.
+fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut limbs = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &limb) in a.iter().enumerate() {
+        let take = *b.get(i).unwrap_or(&0) as u64 + borrow;
+        let limb = limb as u64;
+        if limb >= take {
+            limbs.push((limb - take) as u32);
+            borrow = 0;
+        } else {
+            limbs.push((limb + BASE - take) as u32);
+            borrow = 1;
+        }
+    }
+    trim(&mut limbs);
+    limbs
+}
+
+fn mul_small(a: &[u32], factor: u64) -> Vec<u32> {
+    let mut limbs = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0;
+    for &limb in a {
+        let product = limb as u64 * factor + carry;
+        limbs.push((product % BASE) as u32);
+        carry = product / BASE;
+    }
+    if carry > 0 {
+        limbs.push(carry as u32);
+    }
+    trim(&mut limbs);
+    limbs
+}
+
+/// Long division of magnitudes, finding each limb of the quotient by binary
+/// search. `b` must be nonzero.
+fn divmod_magnitude(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut quotient = vec![0; a.len()];
+    let mut remainder = Vec::new();
+    for (i, &limb) in a.iter().enumerate().rev() {
+        remainder.insert(0, limb);
+        trim(&mut remainder);
+        let (mut lo, mut hi) = (0, BASE - 1);
+        while lo < hi {
+            let mid = (lo + hi).div_ceil(2);
+            if cmp_magnitude(&mul_small(b, mid), &remainder) == Ordering::Greater {
+                hi = mid - 1;
+            } else {
+                lo = mid;
+            }
+        }
+        remainder = sub_magnitude(&remainder, &mul_small(b, lo));
+        quotient[i] = lo as u32;
+    }
+    trim(&mut quotient);
+    (quotient, remainder)
+}
+
+impl BigInt {
+    fn new(negative: bool, mut limbs: Vec<u32>) -> BigInt {
+        trim(&mut limbs);
+        BigInt { negative: negative && !limbs.is_empty(), limbs }
+    }
+
+    /// The value as an `isize`, if it fits in one.
+    pub fn to_isize(&self) -> Option<isize> {
+        let mut magnitude: i128 = 0;
+        for &limb in self.limbs.iter().rev() {
+            magnitude = magnitude.checked_mul(BASE as i128)?.checked_add(limb as i128)?;
+        }
+        isize::try_from(if self.negative { -magnitude } else { magnitude }).ok()
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::new(!self.negative, self.limbs.clone())
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::new(false, self.limbs.clone())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitude(&self.limbs, &other.limbs));
+        }
+        match cmp_magnitude(&self.limbs, &other.limbs) {
+            Ordering::Less => BigInt::new(other.negative, sub_magnitude(&other.limbs, &self.limbs)),
+            _ => BigInt::new(self.negative, sub_magnitude(&self.limbs, &other.limbs)),
+        }
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        let mut limbs = vec![0u64; self.limbs.len() + other.limbs.len()];
+        for (i, &a) in self.limbs.iter().enumerate() {
+            let mut carry = 0;
+            for (j, &b) in other.limbs.iter().enumerate() {
+                let product = limbs[i + j] + a as u64 * b as u64 + carry;
+                limbs[i + j] = product % BASE;
+                carry = product / BASE;
+            }
+            limbs[i + other.limbs.len()] += carry;
+        }
+        BigInt::new(self.negative != other.negative, limbs.into_iter().map(|limb| limb as u32).collect())
+    }
+
+    /// The quotient rounded toward zero and the remainder, which takes the
+    /// sign of `self`, or `None` when dividing by zero.
+    pub fn divmod(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = divmod_magnitude(&self.limbs, &other.limbs);
+        Some((BigInt::new(self.negative != other.negative, quotient), BigInt::new(self.negative, remainder)))
+    }
+
+    /// The greatest common divisor of the magnitudes, which is zero only if
+    /// both are.
+    pub fn gcd(&self, other: &BigInt) -> BigInt {
+        let (mut a, mut b) = (self.abs(), other.abs());
+        while let Some((_, remainder)) = a.divmod(&b) {
+            (a, b) = (b, remainder);
+        }
+        a
+    }
+}
+
+impl From<isize> for BigInt {
+    fn from(value: isize) -> BigInt {
+        let mut magnitude = value.unsigned_abs() as u128;
+        let mut limbs = Vec::new();
+        while magnitude > 0 {
+            limbs.push((magnitude % BASE as u128) as u32);
+            magnitude /= BASE as u128;
+        }
+        BigInt::new(value < 0, limbs)
+    }
+}
+
+/// Parses an optionally negative run of decimal digits.
+impl std::str::FromStr for BigInt {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<BigInt, String> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("invalid integer {:?}", text))
+        }
+        let limbs = digits.as_bytes().rchunks(9)
+            .map(|chunk| chunk.iter().fold(0, |limb, digit| limb * 10 + (digit - b'0') as u32))
+            .collect();
+        Ok(BigInt::new(negative, limbs))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitude(&self.limbs, &other.limbs),
+            (true, true) => cmp_magnitude(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.negative {
+            write!(f,"-")?;
+        }
+        match self.limbs.split_last() {
+            None => write!(f,"0"),
+            Some((last, rest)) => {
+                write!(f,"{}",last)?;
+                for limb in rest.iter().rev() {
+                    write!(f,"{:09}",limb)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,20 +1,41 @@
 use nom::{
     IResult,
     branch::alt,
-    bytes::complete::{tag, take_while1},
+    bytes::complete::{tag, take_while, take_while1},
     multi::many0,
     sequence::delimited,
-    error::ParseError,
+    error::{ParseError, ErrorKind, make_error},
     character::complete::{multispace0},
 };
 
-use std::{error::Error, collections::HashMap, cmp::Ordering};
+#[cfg(feature = "regex")]
+mod regex;
+
+#[cfg(feature = "bignum")]
+mod bigint;
+
+#[cfg(feature = "bignum")]
+pub use bigint::BigInt;
+
+#[cfg(feature = "serde")]
+mod serialization;
+
+use std::{error::Error, collections::{HashMap, HashSet}, cmp::Ordering, borrow::Cow, cell::{Cell, RefCell}, rc::Rc, time::{Duration, Instant}};
 
 #[derive(Clone,Debug,PartialEq)]
 pub enum Atom<'a> {
     Symbol(&'a str),
-    String(&'a str),
+    /// Borrowed from the source when parsed, and owned when built at runtime
+    /// by builtins like `str-concat`, so new strings need no extra variant.
+    String(Cow<'a, str>),
     Number(isize),
+    Char(char),
+    /// An exact fraction in lowest terms, with `den` always above one.
+    Ratio { num: isize, den: isize },
+    /// An integer too large for `Number`, which arithmetic promotes to
+    /// instead of overflowing.
+    #[cfg(feature = "bignum")]
+    BigInt(BigInt),
 }
 
 #[derive(Clone,Debug)]
@@ -23,19 +44,91 @@ pub enum Elem<'a> {
     Single(Atom<'a>),
     Call(Vec<Elem<'a>>),
     List(Vec<Elem<'a>>),
+    Lazy(Seq<'a>),
+    Delay(Rc<RefCell<Delayed<'a>>>),
+}
+
+/// The body of a `delay`, replaced by its value the first time it's forced.
+#[derive(Clone,Debug)]
+pub enum Delayed<'a> {
+    Pending(Elem<'a>),
+    Forced(Elem<'a>),
+}
+
+/// A sequence whose elements are only computed as they're taken.
+#[derive(Clone,Debug)]
+pub enum Seq<'a> {
+    Range(isize, Option<isize>),
+    Map(Box<Elem<'a>>, Box<Elem<'a>>),
+    Filter(Box<Elem<'a>>, Box<Elem<'a>>),
+}
+
+/// Structural equality. A `Call` and a `List` with equal elements are equal,
+/// as are an `Atom` and a `Single` holding the same atom, since evaluation
+/// treats each pair interchangeably as data.
+impl<'a> PartialEq for Elem<'a> {
+    fn eq(&self, other: &Elem<'a>) -> bool {
+        match (self, other) {
+            (Elem::Atom(a) | Elem::Single(a), Elem::Atom(b) | Elem::Single(b)) => a == b,
+            (Elem::Call(a) | Elem::List(a), Elem::Call(b) | Elem::List(b)) => a == b,
+            _ => false
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum EvalError {
-    Unreachable
+    Unreachable,
+    MissingArgument(String),
+    Parse(String),
+    InvalidArgument(String),
+    Overflow(String),
+    LimitExceeded(String),
+    InvalidPattern(String),
+    Io(String),
+    Timeout(Duration),
+    /// Another error, along with the printed form that raised it.
+    InForm(String, Box<EvalError>),
 }
 
+pub type EvalResult<'a> = Result<Elem<'a>, EvalError>;
+
 impl Error for EvalError {
 }
 
-impl<'a> std::fmt::Display for EvalError {
+impl EvalError {
+    /// Attaches `form` as the expression that failed, unless a more deeply
+    /// nested form has already been attached.
+    fn in_form(self, form: &Elem<'_>) -> EvalError {
+        match self {
+            EvalError::InForm(..) => self,
+            _ => EvalError::InForm(form.to_string(), Box::new(self)),
+        }
+    }
+
+    /// The underlying error, without any form attached.
+    pub fn root_cause(&self) -> &EvalError {
+        match self {
+            EvalError::InForm(_, cause) => cause.root_cause(),
+            _ => self,
+        }
+    }
+}
+
+impl std::fmt::Display for EvalError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f,"Eval Error...")
+        match self {
+            EvalError::Unreachable => write!(f,"Eval Error..."),
+            EvalError::MissingArgument(name) => write!(f,"Eval Error: missing argument to {}",name),
+            EvalError::Parse(message) => write!(f,"Parse Error: {}",message),
+            EvalError::InvalidArgument(name) => write!(f,"Eval Error: invalid argument to {}",name),
+            EvalError::Overflow(name) => write!(f,"Eval Error: overflow in {}",name),
+            EvalError::LimitExceeded(name) => write!(f,"Eval Error: limit exceeded in {}",name),
+            EvalError::InvalidPattern(message) => write!(f,"Eval Error: invalid pattern: {}",message),
+            EvalError::Io(message) => write!(f,"IO Error: {}",message),
+            EvalError::Timeout(limit) => write!(f,"Eval Error: timed out after {:?}",limit),
+            EvalError::InForm(form, cause) => write!(f,"{} in {}",cause,form),
+        }
     }
 }
 
@@ -72,7 +165,9 @@ impl<'a> std::fmt::Display for Elem<'a> {
             Elem::Single(atom) => {
                 write!(f,"#")?;
                 write!(f,"{}",atom)
-            }
+            },
+            Elem::Lazy(_) => write!(f,"<lazy>"),
+            Elem::Delay(_) => write!(f,"<delay>")
         }
     }
 }
@@ -81,20 +176,31 @@ impl<'a> std::fmt::Display for Atom<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
             Atom::Number(value) => write!(f,"{}",value),
+            Atom::Ratio { num, den } => write!(f,"{}/{}",num,den),
+            #[cfg(feature = "bignum")]
+            Atom::BigInt(value) => write!(f,"{}",value),
+            Atom::Char(' ') => write!(f,"\\space"),
+            Atom::Char('\n') => write!(f,"\\newline"),
+            Atom::Char('\t') => write!(f,"\\tab"),
+            Atom::Char(value) => write!(f,"\\{}",value),
             Atom::String(value) => write!(f,"\"{}\"",value),
-            Atom::Symbol(name) => write!(f,"{}",name),
+            Atom::Symbol(name) => if name.is_empty() || !name.chars().all(is_atom) {
+                write!(f,"|{}|",name)
+            } else {
+                write!(f,"{}",name)
+            },
         }
     }
 }
 
-fn ws<'a, F: 'a, O, E: ParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, E> where
-    F: Fn(&'a str) -> IResult<&'a str, O, E>
+fn ws<'a, F, O, E: ParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, E> where
+    F: 'a + Fn(&'a str) -> IResult<&'a str, O, E>
 {
     delimited(multispace0, inner, multispace0)
 }
 
-fn dq<'a, F: 'a, O, E: ParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, E> where
-    F: Fn(&'a str) -> IResult<&'a str, O, E>
+fn dq<'a, F, O, E: ParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, E> where
+    F: 'a + Fn(&'a str) -> IResult<&'a str, O, E>
 {
     delimited(tag("\""), inner, tag("\""))
 }
@@ -108,333 +214,3454 @@ fn is_string(c: char) -> bool {
 }
 
 fn is_number(c: char) -> bool {
-    c.is_digit(10) || c == '-'
+    c.is_ascii_digit() || c == '-'
 }
 
-fn number(input: &str) -> IResult<&str, Elem> {
-    let (input, svalue) = take_while1(is_number)(input)?;
-    Ok((input,Elem::Atom(Atom::Number(isize::from_str_radix(svalue, 10).unwrap()))))
+fn number(input: &str) -> IResult<&str, Elem<'_>> {
+    let (rest, svalue) = take_while1(is_number)(input)?;
+    match svalue.parse() {
+        Ok(value) => Ok((rest,Elem::Atom(Atom::Number(value)))),
+        Err(_) => Err(nom::Err::Error(make_error(input, ErrorKind::Digit)))
+    }
 }
 
-fn symbol(input: &str) -> IResult<&str, Elem> {
+fn symbol(input: &str) -> IResult<&str, Elem<'_>> {
     let (input, name) = take_while1(is_atom)(input)?;
     Ok((input,Elem::Atom(Atom::Symbol(name))))
 }
 
-fn string(input: &str) -> IResult<&str, Elem> {
-    let (input, name) = dq(take_while1(is_string))(input)?;
-    Ok((input,Elem::Atom(Atom::String(name))))
+fn pipe_symbol(input: &str) -> IResult<&str, Elem<'_>> {
+    let (input, name) = delimited(tag("|"), take_while(|c| c != '|'), tag("|"))(input)?;
+    Ok((input,Elem::Atom(Atom::Symbol(name))))
+}
+
+fn character(input: &str) -> IResult<&str, Elem<'_>> {
+    let (input, _) = tag("\\")(input)?;
+    let (rest, name) = take_while(is_atom)(input)?;
+    let value = match name {
+        "space" => ' ',
+        "newline" => '\n',
+        "tab" => '\t',
+        "" => match input.chars().next() {
+            Some(value) => return Ok((&input[value.len_utf8()..],Elem::Atom(Atom::Char(value)))),
+            None => return Err(nom::Err::Error(make_error(input, ErrorKind::Char)))
+        },
+        _ => {
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(value), None) => value,
+                _ => return Err(nom::Err::Error(make_error(input, ErrorKind::Char)))
+            }
+        }
+    };
+    Ok((rest,Elem::Atom(Atom::Char(value))))
+}
+
+fn string(input: &str) -> IResult<&str, Elem<'_>> {
+    let (input, name) = dq(take_while(is_string))(input)?;
+    Ok((input,Elem::Atom(Atom::String(Cow::Borrowed(name)))))
 }
 
-fn atom(input: &str) -> IResult<&str, Elem> {
-    alt((string,number,symbol))(input)
+fn atom(input: &str) -> IResult<&str, Elem<'_>> {
+    alt((string,pipe_symbol,character,number,symbol))(input)
 }
 
-fn single(input: &str) -> IResult<&str, Elem> {
+fn single(input: &str) -> IResult<&str, Elem<'_>> {
     let (input, _) = tag("#")(input)?;
     let (input, name) = take_while1(is_atom)(input)?;
     Ok((input,Elem::Single(Atom::Symbol(name))))
 }
 
-fn call(input: &str) -> IResult<&str, Elem> {
+fn quoted(input: &str) -> IResult<&str, Elem<'_>> {
+    let (input, _) = tag("'")(input)?;
+    let (input, quoted) = expr(input)?;
+    Ok((input,Elem::Call(vec![Elem::Atom(Atom::Symbol("quote")),quoted])))
+}
+
+fn call(input: &str) -> IResult<&str, Elem<'_>> {
     let (input, _) = tag("(")(input)?;
     let (input, items) = many0(expr)(input)?;
     let (input, _) = tag(")")(input)?;
     Ok((input, Elem::Call(items)))
 }
 
-fn list(input: &str) -> IResult<&str, Elem> {
+fn list(input: &str) -> IResult<&str, Elem<'_>> {
     let (input, _) = tag("[")(input)?;
     let (input, items) = many0(expr)(input)?;
     let (input, _) = tag("]")(input)?;
     Ok((input, Elem::List(items)))
 }
 
-fn expr(input: &str) -> IResult<&str, Elem> {
-    alt((ws(single),ws(list),ws(call),ws(atom)))(input)
+fn expr(input: &str) -> IResult<&str, Elem<'_>> {
+    alt((ws(single),ws(quoted),ws(list),ws(call),ws(atom)))(input)
 }
 
-impl<'a> Elem<'a> {
-    fn eval(self, env: &mut HashMap<&'a str,Elem<'a>>) -> Elem<'a> {
-        match self {
-            Elem::Atom(_) => self.eval_atom(env),
-            Elem::List(_) => self,
-            Elem::Call(_) => self.eval_call(env),
-            Elem::Single(value) => Elem::Atom(value)
-        }
-    }
+/// A builtin is handed the whole call, its own name included, with the
+/// arguments unevaluated so it can choose which to evaluate and when.
+pub type BuiltinFn = for<'a> fn(&[Elem<'a>], &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a>;
 
-    fn eval_atom(self, env: &mut HashMap<&'a str,Elem<'a>>) -> Elem<'a> {
-        if let Elem::Atom(Atom::Symbol(name)) = self {
-            if env.contains_key(name) {
-                env[name].clone()
-            } else {
-                self
+const BUILTINS: &[(&str, BuiltinFn)] = &[
+    ("add", |items, env| Elem::Call(items.to_vec()).eval_nested(env)),
+    ("list", |items, env| Elem::Call(items.to_vec()).eval_nested(env)),
+    ("cons", |items, env| Ok(eval_arg(items,1,env)?.cons(eval_arg(items,2,env)?))),
+    ("append", |items, env| {
+        let mut appended = eval_arg(items,1,env)?.rcons(eval_arg(items,2,env)?);
+        for index in 3..items.len() {
+            appended = appended.rcons(eval_arg(items,index,env)?);
+        }
+        Ok(appended)
+    }),
+    ("head", |items, env| match eval_arg(items,1,env)? {
+        Elem::Lazy(seq) => Ok(seq.uncons(env)?.map(|(first, _)| first).unwrap_or(Elem::List(vec![]))),
+        value => Ok(value.car()),
+    }),
+    ("tail", |items, env| match eval_arg(items,1,env)? {
+        Elem::Lazy(seq) => Ok(seq.uncons(env)?.map(|(_, rest)| rest).unwrap_or(Elem::List(vec![]))),
+        value => Ok(value.cdr()),
+    }),
+    ("atom", |items, env| Ok(eval_arg(items,1,env)?.atom())),
+    ("not", |items, env| Ok(eval_arg(items,1,env)?.not())),
+    ("eq", |items, env| Ok(eval_arg(items,1,env)?.eq(eval_arg(items,2,env)?))),
+    ("ne", |items, env| Ok(eval_arg(items,1,env)?.ne(eval_arg(items,2,env)?))),
+    ("equal", |items, env| Ok(eval_arg(items,1,env)?.equal(eval_arg(items,2,env)?))),
+    ("lt", |items, env| Ok(eval_arg(items,1,env)?.compare(eval_arg(items,2,env)?,Ordering::Less))),
+    ("gt", |items, env| Ok(eval_arg(items,1,env)?.compare(eval_arg(items,2,env)?,Ordering::Greater))),
+    ("le", |items, env| Ok(eval_arg(items,1,env)?.compare(eval_arg(items,2,env)?,Ordering::Greater).not())),
+    ("ge", |items, env| Ok(eval_arg(items,1,env)?.compare(eval_arg(items,2,env)?,Ordering::Less).not())),
+    ("if", |items, env| eval_arg(items,1,env)?.ifelse(arg(items,2)?,arg(items,3)?,env)),
+    ("cond", |items, env| Elem::cond(items,env)),
+    ("update", |items, env| eval_arg(items,2,env)?.update(eval_arg(items,1,env)?,eval_arg(items,3,env)?,env)),
+    ("with-env", |items, env| eval_arg(items,1,env)?.with_env(arg(items,2)?,env)),
+    ("take-nth", |items, env| eval_arg(items,2,env)?.take_nth(eval_arg(items,1,env)?)),
+    ("scan", |items, env| eval_arg(items,3,env)?.scan(eval_arg(items,1,env)?,eval_arg(items,2,env)?,env)),
+    ("find", |items, env| eval_arg(items,2,env)?.find(eval_arg(items,1,env)?,env)),
+    ("remove", |items, env| eval_arg(items,2,env)?.keep(eval_arg(items,1,env)?,false,env)),
+    ("nil?", |items, env| Ok(Elem::truth(eval_arg(items,1,env)?.is_nil()))),
+    ("false?", |items, env| Ok(Elem::truth(eval_arg(items,1,env)?.is_nil()))),
+    ("true?", |items, env| Ok(Elem::truth(eval_arg(items,1,env)? == Elem::truth(true)))),
+    ("inc", |items, env| eval_arg(items,1,env)?.offset(1,"inc")),
+    ("dec", |items, env| eval_arg(items,1,env)?.offset(-1,"dec")),
+    ("repeat-str", |items, env| eval_arg(items,2,env)?.repeat_str(eval_arg(items,1,env)?)),
+    ("trim", |items, env| eval_arg(items,1,env)?.trim_with(str::trim,"trim")),
+    ("trim-start", |items, env| eval_arg(items,1,env)?.trim_with(str::trim_start,"trim-start")),
+    ("trim-end", |items, env| eval_arg(items,1,env)?.trim_with(str::trim_end,"trim-end")),
+    ("starts-with?", |items, env| eval_arg(items,1,env)?.affix(eval_arg(items,2,env)?,|value, affix| value.starts_with(affix),"starts-with?")),
+    ("ends-with?", |items, env| eval_arg(items,1,env)?.affix(eval_arg(items,2,env)?,|value, affix| value.ends_with(affix),"ends-with?")),
+    ("lines", |items, env| eval_arg(items,1,env)?.split_with(|value| value.lines().collect(),"lines")),
+    ("words", |items, env| eval_arg(items,1,env)?.split_with(|value| value.split_whitespace().collect(),"words")),
+    ("zero-pad", |items, env| eval_arg(items,2,env)?.zero_pad(eval_arg(items,1,env)?)),
+    ("clamp", |items, env| eval_arg(items,3,env)?.clamp(eval_arg(items,1,env)?,eval_arg(items,2,env)?)),
+    ("gcd", |items, env| fold_numbers(items,eval_numbers(items,env)?,gcd)),
+    ("lcm", |items, env| fold_numbers(items,eval_numbers(items,env)?,lcm)),
+    ("rand", |items, env| {
+        if items.len() == 1 {
+            return Ok(Elem::Atom(Atom::Number(next_random() as isize)));
+        }
+        match eval_arg(items,1,env)?.as_number() {
+            Some(bound) if bound > 0 => Ok(Elem::Atom(Atom::Number(random_below(bound as usize) as isize))),
+            _ => Err(EvalError::InvalidArgument("rand".to_string()))
+        }
+    }),
+    ("shuffle", |items, env| Ok(eval_arg(items,1,env)?.shuffle())),
+    ("rand-nth", |items, env| {
+        let items = eval_arg(items,1,env)?.into_items().unwrap_or_default();
+        if items.is_empty() {
+            return Ok(Elem::List(vec![]));
+        }
+        Ok(items[random_below(items.len())].clone())
+    }),
+    ("sample", |items, env| eval_arg(items,2,env)?.sample(eval_arg(items,1,env)?)),
+    ("naturals", |_, _| Ok(Elem::Lazy(Seq::Range(0, None)))),
+    ("lazy-range", |items, env| {
+        let (start, end) = if items.len() > 2 {
+            (eval_arg(items,1,env)?, eval_arg(items,2,env)?)
+        } else {
+            (Elem::Atom(Atom::Number(0)), eval_arg(items,1,env)?)
+        };
+        match (start.as_number(), end.as_number()) {
+            (Some(start), Some(end)) => Ok(Elem::Lazy(Seq::Range(start, Some(end)))),
+            _ => Err(EvalError::InvalidArgument("lazy-range".to_string()))
+        }
+    }),
+    ("take", |items, env| eval_arg(items,2,env)?.take(eval_arg(items,1,env)?,env)),
+    ("map", |items, env| eval_arg(items,2,env)?.map(eval_arg(items,1,env)?,env)),
+    ("filter", |items, env| match eval_arg(items,2,env)? {
+        Elem::Lazy(seq) => Ok(Elem::Lazy(Seq::Filter(Box::new(eval_arg(items,1,env)?), Box::new(Elem::Lazy(seq))))),
+        value => value.keep(eval_arg(items,1,env)?,true,env),
+    }),
+    ("realize", |items, env| eval_arg(items,1,env)?.realize(env)),
+    ("delay", |items, _| Ok(Elem::Delay(Rc::new(RefCell::new(Delayed::Pending(arg(items,1)?)))))),
+    ("force", |items, env| eval_arg(items,1,env)?.force(env)),
+    ("cond->", |items, env| {
+        let mut value = eval_arg(items,1,env)?;
+        for clause in &items[2..] {
+            match clause {
+                Elem::List(pair) if pair.len() == 2 => if eval_arg(pair,0,env)?.truthy() {
+                    value = arg(pair,1)?.thread(value).eval(env)?;
+                },
+                _ => return Err(EvalError::InvalidArgument("cond->".to_string()))
             }
+        }
+        Ok(value)
+    }),
+    ("to-list", |items, env| match eval_arg(items,1,env)? {
+        Elem::Atom(Atom::String(value)) => Ok(Elem::List(value.chars().map(|c| Elem::Atom(Atom::Char(c))).collect())),
+        value => value.into_items().map(Elem::List).ok_or(EvalError::InvalidArgument("to-list".to_string())),
+    }),
+    ("join", |items, env| eval_arg(items,1,env)?.join()),
+    ("ord", |items, env| {
+        let value = eval_arg(items,1,env)?;
+        let c = match &value {
+            Elem::Atom(Atom::Char(c)) => Some(*c),
+            _ => value.as_str().and_then(|value| {
+                let mut chars = value.chars();
+                chars.next().filter(|_| chars.next().is_none())
+            })
+        };
+        c.map(|c| Elem::Atom(Atom::Number(c as isize))).ok_or(EvalError::InvalidArgument("ord".to_string()))
+    }),
+    ("chr", |items, env| Ok(eval_arg(items,1,env)?.as_number()
+        .and_then(|value| u32::try_from(value).ok())
+        .and_then(char::from_u32)
+        .map(|c| Elem::Atom(Atom::String(Cow::Owned(c.to_string()))))
+        .unwrap_or(Elem::List(vec![])))),
+    ("count-matches", |items, env| {
+        let (needle, haystack) = (eval_arg(items,1,env)?, eval_arg(items,2,env)?);
+        match (needle.as_str(), haystack.as_str()) {
+            (Some(""), Some(_)) => Ok(Elem::Atom(Atom::Number(0))),
+            (Some(needle), Some(haystack)) => Ok(Elem::Atom(Atom::Number(haystack.matches(needle).count() as isize))),
+            _ => Err(EvalError::InvalidArgument("count-matches".to_string()))
+        }
+    }),
+    ("defn", |items, env| {
+        if let Elem::Atom(Atom::Symbol(name)) = arg(items,1)? {
+            let mut fun = vec![Elem::Atom(Atom::Symbol("fun")), arg(items,2)?, arg(items,3)?];
+            fun.extend(items.get(4).cloned());
+            env.insert(fold_case(name), Elem::Call(fun));
+            Ok(items[1].clone())
         } else {
-            self
+            Err(EvalError::InvalidArgument("defn".to_string()))
         }
-    }
-
-    fn eval_call(self, env: &mut HashMap<&'a str,Elem<'a>>) -> Elem<'a> {
-        if let Elem::Call(ref items) = self {
-            if items.len() == 0 {
-                return self
+    }),
+    ("doc", |items, env| match eval_arg(items,1,env)? {
+        Elem::Call(fun) if fun.len() > 3 && matches!(fun[2], Elem::Atom(Atom::String(_))) => Ok(fun[2].clone()),
+        _ => Ok(Elem::List(vec![]))
+    }),
+    ("builtins", |_, _| Ok(Elem::List(builtin_names().into_iter().map(|name| Elem::Atom(Atom::Symbol(name))).collect()))),
+    ("apropos", |items, env| match eval_arg(items,1,env)?.as_str() {
+        Some(part) => Ok(Elem::List(builtin_names().into_iter().filter(|name| name.contains(part)).map(|name| Elem::Atom(Atom::Symbol(name))).collect())),
+        None => Err(EvalError::InvalidArgument("apropos".to_string()))
+    }),
+    ("let", |items, env| {
+        if let Elem::Atom(Atom::Symbol(name)) = arg(items,1)? {
+            env.insert(fold_case(name), arg(items,2)?);
+            Ok(items[1].clone())
+        } else {
+            Ok(Elem::Call(items.to_vec()))
+        }
+    }),
+    ("spy", |items, env| {
+        let (label, value) = (eval_arg(items,1,env)?, eval_arg(items,2,env)?);
+        match label.as_str() {
+            Some(label) => write_line(&format!("{}: {}", label, value)),
+            None => write_line(&format!("{}: {}", label, value))
+        }
+        Ok(value)
+    }),
+    ("with-timeout", |items, env| {
+        let limit = match eval_arg(items,1,env)?.as_number() {
+            Some(millis) if millis >= 0 => Duration::from_millis(millis as u64),
+            _ => return Err(EvalError::InvalidArgument("with-timeout".to_string()))
+        };
+        let outer = DEADLINE.with(Cell::get);
+        let deadline = Instant::now() + limit;
+        if outer.is_none_or(|(outer, _)| deadline < outer) {
+            DEADLINE.with(|current| current.set(Some((deadline, limit))));
+        }
+        let result = eval_arg(items,2,env);
+        DEADLINE.with(|current| current.set(outer));
+        result
+    }),
+    ("quote", |items, _| Ok(arg(items,1)?.into_data())),
+    ("mul", |items, env| {
+        let mut product = Atom::Number(1);
+        for index in 1..items.len() {
+            match eval_arg(items,index,env)? {
+                Elem::Atom(factor) | Elem::Single(factor) => product = Arith::Mul.apply(&product, &factor)?,
+                _ => return Err(EvalError::InvalidArgument("mul".to_string()))
             }
-            match items[0] {
-                Elem::Atom(Atom::Symbol("cons")) => items[1].clone().eval(env).cons(items[2].clone().eval(env)),
-                Elem::Atom(Atom::Symbol("append")) => items[1].clone().eval(env).rcons(items[2].clone().eval(env)),
-                Elem::Atom(Atom::Symbol("list")) => {
-                    let mut ls = Vec::new();
-                    let mut first=true;
-                    for item in items {
-                        if first {
-                            first=false;
-                            continue;
-                        }
-                        ls.push(item.clone().eval(env));
-                    }
-                    Elem::List(ls)
-                },
-                Elem::Atom(Atom::Symbol("head")) => items[1].clone().eval(env).car(),
-                Elem::Atom(Atom::Symbol("tail")) => items[1].clone().eval(env).cdr(),
-                Elem::Atom(Atom::Symbol("atom")) => items[1].clone().eval(env).atom(),
-                Elem::Atom(Atom::Symbol("not")) => items[1].clone().eval(env).not(),
-                Elem::Atom(Atom::Symbol("eq")) => items[1].clone().eval(env).eq(items[2].clone().eval(env)),
-                Elem::Atom(Atom::Symbol("ne")) => items[1].clone().eval(env).ne(items[2].clone().eval(env)),
-                Elem::Atom(Atom::Symbol("lt")) => items[1].clone().eval(env).compare(items[2].clone().eval(env),Ordering::Less),
-                Elem::Atom(Atom::Symbol("gt")) => items[1].clone().eval(env).compare(items[2].clone().eval(env),Ordering::Greater),
-                Elem::Atom(Atom::Symbol("le")) => items[1].clone().eval(env).compare(items[2].clone().eval(env),Ordering::Greater).not(),
-                Elem::Atom(Atom::Symbol("ge")) => items[1].clone().eval(env).compare(items[2].clone().eval(env),Ordering::Less).not(),
-                Elem::Atom(Atom::Symbol("if")) => items[1].clone().eval(env).ifelse(items[2].clone(),items[3].clone(),env),
-                Elem::Atom(Atom::Symbol("cond")) => self.clone().cond(items.clone(),env),
-                Elem::Atom(Atom::Symbol("add")) => {
-                    let mut sum=0;
-                    for item in items {
-                        if let Elem::Atom(Atom::Number(addend)) = item.clone().eval(env) {
-                            sum += addend;
-                        }
+        }
+        Ok(Elem::Atom(product))
+    }),
+    ("sub", |items, env| {
+        let mut difference = match eval_arg(items,1,env)? {
+            Elem::Atom(first) | Elem::Single(first) => first,
+            _ => return Err(EvalError::InvalidArgument("sub".to_string()))
+        };
+        if items.len() == 2 {
+            return Ok(Elem::Atom(Arith::Sub.apply(&Atom::Number(0), &difference)?))
+        }
+        for index in 2..items.len() {
+            match eval_arg(items,index,env)? {
+                Elem::Atom(subtrahend) | Elem::Single(subtrahend) => difference = Arith::Sub.apply(&difference, &subtrahend)?,
+                _ => return Err(EvalError::InvalidArgument("sub".to_string()))
+            }
+        }
+        Ok(Elem::Atom(difference))
+    }),
+    ("div", |items, env| {
+        let mut quotient = match eval_arg(items,1,env)? {
+            Elem::Atom(first) | Elem::Single(first) => first,
+            _ => return Err(EvalError::InvalidArgument("div".to_string()))
+        };
+        for index in 2..items.len() {
+            match eval_arg(items,index,env)? {
+                Elem::Atom(divisor) | Elem::Single(divisor) => quotient = Arith::Div.apply(&quotient, &divisor)?,
+                _ => return Err(EvalError::InvalidArgument("div".to_string()))
+            }
+        }
+        Ok(Elem::Atom(quotient))
+    }),
+    ("deftype", |items, _| match (arg(items,1)?, arg(items,2)?) {
+        (Elem::Atom(Atom::Symbol(name)), Elem::List(fields)) => {
+            let mut names = Vec::new();
+            for field in fields {
+                match field {
+                    Elem::Atom(Atom::Symbol(field)) => names.push(intern(fold_case(field))),
+                    _ => return Err(EvalError::InvalidArgument("deftype".to_string()))
+                }
+            }
+            RECORD_TYPES.with(|types| types.borrow_mut().insert(intern(fold_case(name)), names));
+            Ok(items[1].clone())
+        },
+        _ => Err(EvalError::InvalidArgument("deftype".to_string()))
+    }),
+    ("quot", |items, env| {
+        let numbers = eval_numbers(items,env)?;
+        if numbers.iter().skip(1).any(|&divisor| divisor == 0) {
+            return Err(EvalError::InvalidArgument("quot".to_string()))
+        }
+        fold_numbers(items,numbers,isize::checked_div)
+    }),
+    ("match", |items, env| {
+        let value = eval_arg(items,1,env)?;
+        for clause in &items[2..] {
+            match clause {
+                Elem::List(clause) if clause.len() == 2 => {
+                    let mut bindings = Vec::new();
+                    if clause[0].destructure(&value, &mut bindings) {
+                        let mut env_m = env.clone();
+                        env_m.extend(bindings);
+                        return clause[1].clone().eval(&mut env_m)
                     }
-                    Elem::Atom(Atom::Number(sum))
                 },
-                Elem::Atom(Atom::Symbol("let")) => {
-                    if let Elem::Atom(Atom::Symbol(name)) = items[1].clone() {
-                        env.insert(name, items[2].clone());
-                        items[1].clone()
-                    } else {
-                        self
+                _ => return Err(EvalError::InvalidArgument("match".to_string()))
+            }
+        }
+        Ok(Elem::List(vec![]))
+    }),
+    ("when-let", |items, env| match bind_if_atom(items,env)? {
+        Some(bindings) => bindings.with_env(arg(items,2)?,env),
+        None => Ok(Elem::List(vec![]))
+    }),
+    ("if-let", |items, env| match bind_if_atom(items,env)? {
+        Some(bindings) => bindings.with_env(arg(items,2)?,env),
+        None => eval_arg(items,3,env)
+    }),
+    ("mod", |items, env| {
+        let numbers = eval_numbers(items,env)?;
+        if numbers.iter().skip(1).any(|&divisor| divisor == 0) {
+            return Err(EvalError::InvalidArgument("mod".to_string()))
+        }
+        fold_numbers(items,numbers,isize::checked_rem_euclid)
+    }),
+    ("min", |items, env| extreme(items,env,Ordering::Less)),
+    ("max", |items, env| extreme(items,env,Ordering::Greater)),
+    ("progn", |items, env| {
+        let mut last = Elem::List(vec![]);
+        for index in 1..items.len() {
+            last = eval_arg(items,index,env)?;
+        }
+        Ok(last)
+    }),
+    ("prog1", |items, env| {
+        let first = eval_arg(items,1,env)?;
+        for index in 2..items.len() {
+            eval_arg(items,index,env)?;
+        }
+        Ok(first)
+    }),
+    ("abs", |items, env| match eval_arg(items,1,env)? {
+        Elem::Atom(atom) | Elem::Single(atom) if atom.cmp_numeric(&Atom::Number(0)) == Some(Ordering::Less) => Arith::Sub.apply(&Atom::Number(0), &atom)
+            .map(Elem::Atom)
+            .map_err(|_| EvalError::Overflow("abs".to_string())),
+        value => Ok(value)
+    }),
+    ("for", |items, env| match arg(items,1)? {
+        Elem::List(clause) if clause.len() == 2 || (clause.len() == 4 && clause[2] == Elem::Atom(Atom::Symbol(":when"))) => {
+            let fun = |body| Elem::Call(vec![Elem::Atom(Atom::Symbol("fun")), Elem::List(vec![clause[0].clone()]), body]);
+            let mut source = clause[1].clone().eval(env)?;
+            if let Elem::Lazy(_) = source {
+                source = source.realize(env)?;
+            }
+            if let Some(guard) = clause.get(3) {
+                source = source.keep(fun(guard.clone()),true,env)?;
+            }
+            source.map(fun(arg(items,2)?),env)
+        },
+        _ => Err(EvalError::InvalidArgument("for".to_string()))
+    }),
+    ("and", |items, env| {
+        let mut last = Elem::truth(true);
+        for index in 1..items.len() {
+            last = eval_arg(items,index,env)?;
+            if !last.truthy() {
+                return Ok(Elem::List(vec![]))
+            }
+        }
+        Ok(last)
+    }),
+    ("every-pred", |items, env| combinator(items,env,"and")),
+    ("some-pred", |items, env| combinator(items,env,"or")),
+    ("or", |items, env| {
+        for index in 1..items.len() {
+            let value = eval_arg(items,index,env)?;
+            if value.truthy() {
+                return Ok(value)
+            }
+        }
+        Ok(Elem::List(vec![]))
+    }),
+    ("juxt", |items, env| combinator(items,env,"list")),
+    ("tap", |items, env| {
+        let value = eval_arg(items,2,env)?;
+        eval_arg(items,1,env)?.apply(vec![value.clone()],env)?;
+        Ok(value)
+    }),
+    ("range", |items, env| {
+        let (start, end, step) = match eval_numbers(items,env)?[..] {
+            [] => return Err(EvalError::MissingArgument("range".to_string())),
+            [end] => (0, end, 1),
+            [start, end] => (start, end, 1),
+            [start, end, step] if step != 0 => (start, end, step),
+            _ => return Err(EvalError::InvalidArgument("range".to_string()))
+        };
+        let mut values = Vec::new();
+        let mut value = Some(start);
+        while let Some(current) = value.filter(|&value| if step > 0 { value < end } else { value > end }) {
+            if values.len() == REALIZE_LIMIT {
+                return Err(EvalError::LimitExceeded("range".to_string()))
+            }
+            values.push(Elem::Atom(Atom::Number(current)));
+            value = current.checked_add(step);
+        }
+        Ok(Elem::List(values))
+    }),
+    ("len", |items, env| Ok(Elem::Atom(Atom::Number(eval_arg(items,1,env)?.as_sequence().map_or(0, <[_]>::len) as isize)))),
+    ("frequencies", |items, env| Ok(Elem::List(eval_arg(items,1,env)?.frequencies().into_iter().map(|(item, count)| {
+        Elem::List(vec![item, Elem::Atom(Atom::Number(count as isize))])
+    }).collect()))),
+    ("mode", |items, env| {
+        let mut best: Option<(Elem, usize)> = None;
+        for (item, count) in eval_arg(items,1,env)?.frequencies() {
+            if best.as_ref().is_none_or(|(_, best)| count > *best) {
+                best = Some((item, count));
+            }
+        }
+        Ok(best.map_or(Elem::List(vec![]), |(item, _)| item))
+    }),
+    ("nth", |items, env| {
+        let index = eval_arg(items,1,env)?.as_number().ok_or_else(|| EvalError::InvalidArgument("nth".to_string()))?;
+        let list = eval_arg(items,2,env)?;
+        let item = usize::try_from(index).ok().and_then(|index| list.as_sequence()?.get(index));
+        Ok(item.cloned().unwrap_or(Elem::List(vec![])))
+    }),
+    ("mean", |items, env| {
+        let numbers = sorted_numbers(items,env)?;
+        if numbers.is_empty() {
+            return Ok(Elem::List(vec![]))
+        }
+        let mut sum = Atom::Number(0);
+        for number in &numbers {
+            sum = Arith::Add.apply(&sum, number)?;
+        }
+        Ok(Elem::Atom(Arith::Div.apply(&sum, &Atom::Number(numbers.len() as isize))?))
+    }),
+    ("median", |items, env| {
+        let numbers = sorted_numbers(items,env)?;
+        let middle = numbers.len() / 2;
+        match numbers.len() {
+            0 => Ok(Elem::List(vec![])),
+            len if len % 2 == 1 => Ok(Elem::Atom(numbers[middle].clone())),
+            _ => {
+                let sum = Arith::Add.apply(&numbers[middle - 1], &numbers[middle])?;
+                Ok(Elem::Atom(Arith::Div.apply(&sum, &Atom::Number(2))?))
+            }
+        }
+    }),
+    ("dedupe", |items, env| {
+        let mut kept: Vec<Elem> = Vec::new();
+        for item in eval_arg(items,1,env)?.into_items().unwrap_or_default() {
+            if kept.last() != Some(&item) {
+                kept.push(item);
+            }
+        }
+        Ok(Elem::List(kept))
+    }),
+    ("fold", |items, env| eval_arg(items,3,env)?.fold(eval_arg(items,1,env)?,eval_arg(items,2,env)?,env)),
+    ("rle-encode", |items, env| {
+        let mut runs: Vec<(Elem, isize)> = Vec::new();
+        for item in eval_arg(items,1,env)?.into_items().unwrap_or_default() {
+            match runs.last_mut() {
+                Some((last, count)) if *last == item => *count += 1,
+                _ => runs.push((item, 1)),
+            }
+        }
+        Ok(Elem::List(runs.into_iter().map(|(item, count)| Elem::List(vec![Elem::Atom(Atom::Number(count)), item])).collect()))
+    }),
+    ("rle-decode", |items, env| {
+        let mut decoded = Vec::new();
+        for run in eval_arg(items,1,env)?.into_items().unwrap_or_default() {
+            match run.into_items().as_deref() {
+                Some([count, item]) if count.as_number().is_some_and(|count| count >= 0) => {
+                    let count = count.as_number().unwrap_or_default() as usize;
+                    if decoded.len() + count > REALIZE_LIMIT {
+                        return Err(EvalError::LimitExceeded("rle-decode".to_string()))
                     }
+                    decoded.extend(std::iter::repeat_n(item.clone(), count));
                 },
-                Elem::Atom(Atom::Symbol(name)) => {
-                    if env.contains_key(name) {
-                        let mut items_m = items.clone();
-                        items_m[0] = env[name].clone();
-                        Elem::Call(items_m).eval(env)
-                    } else {
-                        self
-                    }
+                _ => return Err(EvalError::InvalidArgument("rle-decode".to_string()))
+            }
+        }
+        Ok(Elem::List(decoded))
+    }),
+    ("transpose", |items, env| {
+        let mut rows = Vec::new();
+        for row in eval_arg(items,1,env)?.into_items().unwrap_or_default() {
+            rows.push(row.into_items().ok_or_else(|| EvalError::InvalidArgument("transpose".to_string()))?.into_iter());
+        }
+        let width = rows.iter().map(ExactSizeIterator::len).min().unwrap_or(0);
+        Ok(Elem::List((0..width).map(|_| Elem::List(rows.iter_mut().filter_map(Iterator::next).collect())).collect()))
+    }),
+    ("reverse", |items, env| match eval_arg(items,1,env)? {
+        Elem::List(mut elems) | Elem::Call(mut elems) => {
+            elems.reverse();
+            Ok(Elem::List(elems))
+        },
+        value => Ok(value)
+    }),
+    ("dot", |items, env| match (eval_arg(items,1,env)?.into_items(), eval_arg(items,2,env)?.into_items()) {
+        (Some(a), Some(b)) => dot_product(&a,&b,"dot").map(Elem::Atom),
+        _ => Err(EvalError::InvalidArgument("dot".to_string()))
+    }),
+    ("mat-mul", |items, env| {
+        let invalid = || EvalError::InvalidArgument("mat-mul".to_string());
+        let a = matrix_rows(eval_arg(items,1,env)?).ok_or_else(invalid)?;
+        let b = matrix_rows(eval_arg(items,2,env)?).ok_or_else(invalid)?;
+        let columns: Vec<Vec<Elem>> = (0..b.first().map_or(0, Vec::len)).map(|j| b.iter().map(|row| row[j].clone()).collect()).collect();
+        let mut product = Vec::new();
+        for row in a {
+            if row.len() != b.len() {
+                return Err(invalid())
+            }
+            product.push(Elem::List(columns.iter().map(|column| dot_product(&row,column,"mat-mul").map(Elem::Atom)).collect::<Result<_,_>>()?));
+        }
+        Ok(Elem::List(product))
+    }),
+    ("member", |items, env| {
+        let value = eval_arg(items,1,env)?;
+        Ok(Elem::truth(eval_arg(items,2,env)?.into_items().unwrap_or_default().contains(&value)))
+    }),
+    ("seq?", |items, env| Ok(Elem::truth(eval_arg(items,1,env)?.as_sequence().is_some()))),
+    ("first", |items, env| match eval_arg(items,1,env)? {
+        Elem::Lazy(seq) => Ok(seq.uncons(env)?.map(|(first, _)| first).unwrap_or(Elem::List(vec![]))),
+        value => Ok(value.as_sequence().and_then(<[_]>::first).cloned().unwrap_or(Elem::List(vec![]))),
+    }),
+    ("rest", |items, env| match eval_arg(items,1,env)? {
+        Elem::Lazy(seq) => Ok(seq.uncons(env)?.map(|(_, rest)| rest).unwrap_or(Elem::List(vec![]))),
+        value => Ok(value.cdr()),
+    }),
+    ("assoc", |items, env| {
+        let (key, alist) = (eval_arg(items,1,env)?, eval_arg(items,2,env)?);
+        Ok(alist.assoc(&key).and_then(|index| alist.as_sequence()?.get(index).cloned()).unwrap_or(Elem::List(vec![])))
+    }),
+    ("last", |items, env| Ok(eval_arg(items,1,env)?.as_sequence().and_then(<[_]>::last).cloned().unwrap_or(Elem::List(vec![])))),
+    ("init", |items, env| {
+        let list = eval_arg(items,1,env)?;
+        Ok(Elem::List(list.as_sequence().and_then(<[_]>::split_last).map_or(vec![], |(_, init)| init.to_vec())))
+    }),
+    ("into", |items, env| {
+        let (target, source) = (eval_arg(items,1,env)?, eval_arg(items,2,env)?);
+        let source = match source {
+            Elem::Atom(Atom::String(value)) | Elem::Single(Atom::String(value)) => value.chars().map(|c| Elem::Atom(Atom::Char(c))).collect(),
+            Elem::Lazy(_) => source.realize(env)?.into_items().unwrap_or_default(),
+            _ => source.into_items().ok_or_else(|| EvalError::InvalidArgument("into".to_string()))?,
+        };
+        match target {
+            Elem::Atom(Atom::String(value)) | Elem::Single(Atom::String(value)) => {
+                Elem::List(std::iter::once(Elem::Atom(Atom::String(value))).chain(source).collect()).join()
+            },
+            _ => match target.into_items() {
+                Some(mut target) => {
+                    target.extend(source);
+                    Ok(Elem::List(target))
                 },
-                Elem::Call(ref subitems) => {
-                    match subitems[0] {
-                        Elem::Atom(Atom::Symbol("fun")) => {
-                            let mut env_m = env.clone();
-                            if let Elem::List(names) = subitems[1].clone() {
-                                let mut i=1;
-                                for name in names {
-                                    if let Elem::Atom(Atom::Symbol(name_a)) = name {
-                                        env_m.insert(name_a,items[i].clone().eval(env));
-                                    }
-                                    i+=1;
-                                }
-                                subitems[2].clone().eval(&mut env_m)
-                            } else {
-                                self
-                            }
-                        },
-                        _ => self
-                    }
-                }
-                _ => self
+                None => Err(EvalError::InvalidArgument("into".to_string()))
             }
-        } else {
-            self
         }
+    }),
+    ("flatten", |items, env| {
+        let mut flat = Vec::new();
+        eval_arg(items,1,env)?.flatten(&mut flat);
+        Ok(Elem::List(flat))
+    }),
+    ("pluck", |items, env| {
+        let key = eval_arg(items,1,env)?;
+        let records = eval_arg(items,2,env)?.into_items().unwrap_or_default();
+        Ok(Elem::List(records.iter().map(|record| record.assoc_value(&key).unwrap_or(Elem::List(vec![]))).collect()))
+    }),
+    ("where", |items, env| {
+        let (key, value) = (eval_arg(items,1,env)?, eval_arg(items,2,env)?);
+        let records = eval_arg(items,3,env)?.into_items().unwrap_or_default();
+        Ok(Elem::List(records.into_iter().filter(|record| record.assoc_value(&key).as_ref() == Some(&value)).collect()))
+    }),
+    ("count", |items, env| {
+        let (wanted, list) = (eval_arg(items,1,env)?, eval_arg(items,2,env)?);
+        let mut count = 0;
+        for item in list.into_items().unwrap_or_default() {
+            let counted = if wanted.is_function() {
+                wanted.clone().apply(vec![item], env)?.truthy()
+            } else {
+                item == wanted
+            };
+            count += counted as isize;
+        }
+        Ok(Elem::Atom(Atom::Number(count)))
+    }),
+    ("count-if-not", |items, env| {
+        let pred = eval_arg(items,1,env)?;
+        let list = eval_arg(items,2,env)?.keep(pred,false,env)?;
+        Ok(Elem::Atom(Atom::Number(list.as_sequence().map_or(0, <[_]>::len) as isize)))
+    }),
+    ("index-of", |items, env| index_of(items,env,false)),
+    ("last-index-of", |items, env| index_of(items,env,true)),
+    ("drop", |items, env| eval_arg(items,2,env)?.drop(eval_arg(items,1,env)?,env)),
+    ("splice", |items, env| {
+        let (start, count) = match (eval_arg(items,1,env)?.as_number(), eval_arg(items,2,env)?.as_number()) {
+            (Some(start), Some(count)) => (start.max(0) as usize, count.max(0) as usize),
+            _ => return Err(EvalError::InvalidArgument("splice".to_string()))
+        };
+        let insert = eval_arg(items,3,env)?.into_items().ok_or_else(|| EvalError::InvalidArgument("splice".to_string()))?;
+        let mut target = eval_arg(items,4,env)?.into_items().ok_or_else(|| EvalError::InvalidArgument("splice".to_string()))?;
+        let start = start.min(target.len());
+        let end = start.saturating_add(count).min(target.len());
+        target.splice(start..end, insert);
+        Ok(Elem::List(target))
+    }),
+    ("push", |items, env| Ok(eval_arg(items,1,env)?.cons(eval_arg(items,2,env)?))),
+    ("pop", |items, env| Ok(eval_arg(items,1,env)?.cdr())),
+    ("peek", |items, env| Ok(eval_arg(items,1,env)?.car())),
+    ("enqueue", |items, env| {
+        let element = eval_arg(items,1,env)?;
+        let mut queue = eval_arg(items,2,env)?.as_sequence().map_or(vec![], <[Elem]>::to_vec);
+        queue.push(element);
+        Ok(Elem::List(queue))
+    }),
+    ("dequeue", |items, env| Ok(eval_arg(items,1,env)?.cdr())),
+    ("front", |items, env| Ok(eval_arg(items,1,env)?.car())),
+    ("str-concat", |items, env| {
+        let mut concatenated = String::new();
+        for index in 1..items.len() {
+            match eval_arg(items,index,env)? {
+                Elem::Atom(Atom::String(value)) => concatenated.push_str(&value),
+                _ => return Err(EvalError::InvalidArgument("str-concat".to_string()))
+            }
+        }
+        Ok(Elem::Atom(Atom::String(Cow::Owned(concatenated))))
+    }),
+    ("str-len", |items, env| Ok(Elem::Atom(Atom::Number(match eval_arg(items,1,env)? {
+        Elem::Atom(Atom::String(value)) => value.chars().count() as isize,
+        _ => 0
+    })))),
+    ("substring", |items, env| {
+        match (eval_arg(items,1,env)?, eval_arg(items,2,env)?.as_number(), eval_arg(items,3,env)?.as_number()) {
+            (Elem::Atom(Atom::String(value)), Some(start), Some(end)) => {
+                let start = start.max(0) as usize;
+                let end = (end.max(0) as usize).max(start);
+                Ok(Elem::Atom(Atom::String(Cow::Owned(value.chars().skip(start).take(end - start).collect()))))
+            },
+            _ => Err(EvalError::InvalidArgument("substring".to_string()))
+        }
+    }),
+    ("upcase", |items, env| match eval_arg(items,1,env)? {
+        Elem::Atom(Atom::String(value)) => Ok(Elem::Atom(Atom::String(Cow::Owned(value.to_uppercase())))),
+        _ => Err(EvalError::InvalidArgument("upcase".to_string()))
+    }),
+    ("downcase", |items, env| match eval_arg(items,1,env)? {
+        Elem::Atom(Atom::String(value)) => Ok(Elem::Atom(Atom::String(Cow::Owned(value.to_lowercase())))),
+        _ => Err(EvalError::InvalidArgument("downcase".to_string()))
+    }),
+];
+
+/// The argument of `min` or `max` that's furthest toward `order`, or [] if
+/// there are none.
+fn extreme<'a>(items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>, order: Ordering) -> EvalResult<'a> {
+    let mut best: Option<Elem<'a>> = None;
+    for index in 1..items.len() {
+        let value = eval_arg(items,index,env)?;
+        if !matches!(&value, Elem::Atom(atom) | Elem::Single(atom) if atom.is_numeric()) {
+            return Err(EvalError::InvalidArgument(items[0].to_string()))
+        }
+        best = match best {
+            Some(best) if !value.clone().compare(best.clone(), order).truthy() => Some(best),
+            _ => Some(value)
+        };
     }
+    Ok(best.unwrap_or(Elem::List(vec![])))
+}
 
-    fn cons(self, other:Elem<'a>) -> Elem<'a> {
-        match other {
-            Elem::Call(mut items) | Elem::List(mut items) => {
-                items.insert(0, self);
-                return Elem::List(items);
-            },
-            _ => Elem::List(vec![self, other])  
+/// The parameter of functions built by combinators. Its name has a space in
+/// it so it can't capture a symbol that one of the combined functions uses.
+const COMBINATOR_ARG: &str = "combinator arg";
+
+/// Builds a function of one argument that applies each function given to
+/// the call and passes the results on to the builtin `combine`.
+fn combinator<'a>(items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>, combine: &'static str) -> EvalResult<'a> {
+    let arg = Elem::Atom(Atom::Symbol(COMBINATOR_ARG));
+    let mut body = vec![Elem::Atom(Atom::Symbol(combine))];
+    for index in 1..items.len() {
+        body.push(Elem::Call(vec![eval_arg(items,index,env)?, arg.clone()]));
+    }
+    Ok(Elem::Call(vec![Elem::Atom(Atom::Symbol("fun")), Elem::List(vec![arg]), Elem::Call(body)]))
+}
+
+/// The numbers in the list passed to `mean` or `median`, in order.
+fn sorted_numbers<'a>(items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>) -> Result<Vec<Atom<'a>>, EvalError> {
+    let invalid = || EvalError::InvalidArgument(items[0].to_string());
+    let mut numbers = Vec::new();
+    for item in eval_arg(items,1,env)?.into_items().ok_or_else(invalid)? {
+        match item {
+            Elem::Atom(number) | Elem::Single(number) if number.is_numeric() => numbers.push(number),
+            _ => return Err(invalid())
         }
     }
+    numbers.sort_by(|a, b| a.cmp_numeric(b).unwrap_or(Ordering::Equal));
+    Ok(numbers)
+}
 
-    fn rcons(self, other:Elem<'a>) -> Elem<'a> {
-        match self {
-            Elem::Call(mut items) | Elem::List(mut items) => {
-                items.push(other);
-                return Elem::List(items);
-            },
-            _ => Elem::List(vec![self, other])  
+/// The sum of the products of the numbers in `a` and `b`, which must be the
+/// same length.
+fn dot_product<'a>(a: &[Elem<'a>], b: &[Elem<'a>], name: &str) -> Result<Atom<'a>, EvalError> {
+    if a.len() != b.len() {
+        return Err(EvalError::InvalidArgument(name.to_string()))
+    }
+    let mut sum = Atom::Number(0);
+    for pair in a.iter().zip(b) {
+        match pair {
+            (Elem::Atom(x) | Elem::Single(x), Elem::Atom(y) | Elem::Single(y)) => sum = Arith::Add.apply(&sum, &Arith::Mul.apply(x, y)?)?,
+            _ => return Err(EvalError::InvalidArgument(name.to_string()))
         }
     }
+    Ok(sum)
+}
 
-    fn car(self) -> Elem<'a> {
-        match self {
-            Elem::Call(ref items) | Elem::List(ref items) => {
-                if items.len() == 0 {
-                    return Elem::List(vec![])
-                }
-                return items[0].clone();
-            },
-            _ => self
+/// The rows of a matrix given as a list of lists, each the same length.
+fn matrix_rows(matrix: Elem<'_>) -> Option<Vec<Vec<Elem<'_>>>> {
+    let rows = matrix.into_items()?.into_iter().map(Elem::into_items).collect::<Option<Vec<_>>>()?;
+    rows.iter().all(|row| row.len() == rows[0].len()).then_some(rows)
+}
+
+/// The position of the first or last occurrence of an item in a list, or of
+/// a substring in a string counted in characters, for `index-of` and
+/// `last-index-of`.
+fn index_of<'a>(items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>, last: bool) -> EvalResult<'a> {
+    let (needle, haystack) = (eval_arg(items,1,env)?, eval_arg(items,2,env)?);
+    let index = match (needle.as_str(), haystack.as_str()) {
+        (Some(needle), Some(haystack)) => {
+            let byte = if last { haystack.rfind(needle) } else { haystack.find(needle) };
+            byte.map(|byte| haystack[..byte].chars().count())
+        },
+        _ => match haystack.as_sequence() {
+            Some(list) if last => list.iter().rposition(|item| *item == needle),
+            Some(list) => list.iter().position(|item| *item == needle),
+            None => return Err(EvalError::InvalidArgument(items[0].to_string()))
         }
+    };
+    Ok(index.map_or(Elem::List(vec![]), |index| Elem::Atom(Atom::Number(index as isize))))
+}
+
+/// Evaluates the `[name value]` binding of `when-let` or `if-let`, giving the
+/// bindings to pass to `with_env` if the value is an atom.
+fn bind_if_atom<'a>(items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>) -> Result<Option<Elem<'a>>, EvalError> {
+    match arg(items,1)? {
+        Elem::List(binding) if binding.len() == 2 => {
+            let value = binding[1].clone().eval(env)?;
+            Ok(value.truthy().then(|| Elem::List(vec![Elem::List(vec![binding[0].clone(), value])])))
+        },
+        _ => Err(EvalError::InvalidArgument(items[0].to_string()))
     }
+}
 
-    fn cdr(mut self) -> Elem<'a> {
-        match self {
-            Elem::Call(ref mut items) | Elem::List(ref mut items) => {
-                if items.len() == 0 {
-                    return Elem::List(vec![])
-                }
-                items.remove(0);
-                return Elem::List(items.to_vec());
-            },
-            _ => return Elem::List(vec![])
+#[cfg(feature = "regex")]
+fn regex_match<'a>(items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+    let (pattern, text) = (eval_arg(items,1,env)?, eval_arg(items,2,env)?);
+    let (pattern, text) = match (pattern.as_str(), text.as_str()) {
+        (Some(pattern), Some(text)) => (pattern, text),
+        _ => return Err(EvalError::InvalidArgument("regex-match".to_string()))
+    };
+    let regex = regex::Regex::new(pattern).map_err(EvalError::InvalidPattern)?;
+    Ok(match regex.captures(text, check_timeout)? {
+        Some(groups) => Elem::List(groups.into_iter().map(|group| match group {
+            Some(group) => Elem::Atom(Atom::String(Cow::Owned(group))),
+            None => Elem::List(vec![])
+        }).collect()),
+        None => Elem::List(vec![])
+    })
+}
+
+fn default_registry() -> HashMap<&'static str, BuiltinFn> {
+    let registry = BUILTINS.iter().copied();
+    #[cfg(feature = "regex")]
+    let registry = registry.chain([("regex-match", regex_match as BuiltinFn)]);
+    registry.collect()
+}
+
+/// Adds a native builtin callable from scripts on the current thread,
+/// replacing any builtin already registered under `name`.
+///
+/// Builtins take priority over definitions made with `let`, so a script
+/// can't shadow them.
+pub fn register_builtin(name: &'static str, f: BuiltinFn) {
+    NESTED_BUILTINS.with(|nested| nested.borrow_mut().remove(name));
+    REGISTRY.with(|registry| registry.borrow_mut().insert(name, f));
+}
+
+/// Adds the `save-env` and `load-env` builtins on the current thread. They
+/// read and write files, so scripts only get them when the embedder asks.
+pub fn register_file_builtins() {
+    register_builtin("save-env", |items, env| match eval_arg(items,1,env)?.as_str() {
+        Some(path) => save_env(env, path).map(|_| Elem::truth(true)),
+        None => Err(EvalError::InvalidArgument("save-env".to_string()))
+    });
+    register_builtin("load-env", |items, env| match eval_arg(items,1,env)?.as_str() {
+        Some(path) => load_env(path, env).map(|_| Elem::truth(true)),
+        None => Err(EvalError::InvalidArgument("load-env".to_string()))
+    });
+}
+
+fn builtin(name: &str) -> Option<BuiltinFn> {
+    REGISTRY.with(|registry| registry.borrow().get(name).copied())
+}
+
+/// Every name in the builtin registry, sorted.
+fn builtin_names() -> Vec<&'static str> {
+    REGISTRY.with(|registry| {
+        let mut names: Vec<&'static str> = registry.borrow().keys().copied().collect();
+        names.sort();
+        names
+    })
+}
+
+/// Gets the unevaluated argument at `index` of a call, for use in builtins.
+pub fn arg<'a>(items: &[Elem<'a>], index: usize) -> EvalResult<'a> {
+    match items.get(index) {
+        Some(item) => Ok(item.clone()),
+        None => Err(EvalError::MissingArgument(items.first().map_or_else(|| "()".to_string(), Elem::to_string))),
+    }
+}
+
+/// Evaluates the argument at `index` of a call, for use in builtins.
+pub fn eval_arg<'a>(items: &[Elem<'a>], index: usize, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+    arg(items, index)?.eval(env)
+}
+
+fn eval_numbers<'a>(items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>) -> Result<Vec<isize>, EvalError> {
+    let mut numbers = Vec::new();
+    for index in 1..items.len() {
+        match eval_arg(items,index,env)?.as_number() {
+            Some(value) => numbers.push(value),
+            None => return Err(EvalError::InvalidArgument(items[0].to_string()))
         }
     }
+    Ok(numbers)
+}
 
-    fn atom(self) -> Elem<'a> {
+/// An operation on number atoms that's checked for overflow.
+#[derive(Clone,Copy,PartialEq)]
+enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Arith {
+    fn name(self) -> &'static str {
         match self {
-            Elem::Atom(_) | Elem::Single(_) => Elem::Single(Atom::Symbol("t")),
-            _ => Elem::List(vec![])
+            Arith::Add => "add",
+            Arith::Sub => "sub",
+            Arith::Mul => "mul",
+            Arith::Div => "div",
         }
     }
 
-    fn not(self) -> Elem<'a> {
-        match self {
-            Elem::List(items) | Elem::Call(items) => if items.len() == 0 {
-                Elem::Single(Atom::Symbol("t"))
-            } else {
-                Elem::List(vec![])
+    /// Applies the operation, promoting the result to a bignum if it doesn't
+    /// fit in an `isize` and bignums are enabled, or failing if they aren't.
+    /// Division, and anything involving a ratio, gives an exact ratio.
+    fn apply<'a>(self, a: &Atom<'a>, b: &Atom<'a>) -> Result<Atom<'a>, EvalError> {
+        if let (Atom::Number(a), Atom::Number(b)) = (a, b) {
+            let value = match self {
+                Arith::Add => a.checked_add(*b),
+                Arith::Sub => a.checked_sub(*b),
+                Arith::Mul => a.checked_mul(*b),
+                Arith::Div => None,
+            };
+            if let Some(value) = value {
+                return Ok(Atom::Number(value))
             }
-            _ => Elem::List(vec![])
+        }
+        if let (Some(a), Some(b)) = (a.as_fraction(), b.as_fraction()) {
+            if self == Arith::Div || a.1 != 1 || b.1 != 1 {
+                let result = self.apply_fraction(a, b);
+                if !cfg!(feature = "bignum") || !matches!(result, Err(EvalError::Overflow(_))) {
+                    return result
+                }
+            }
+        }
+        #[cfg(feature = "bignum")]
+        if let (Some(a), Some(b)) = (a.to_big_fraction(), b.to_big_fraction()) {
+            return self.apply_big_fraction(a, b)
+        }
+        match (a, b) {
+            (Atom::Number(_), Atom::Number(_)) => Err(EvalError::Overflow(self.name().to_string())),
+            _ => Err(EvalError::InvalidArgument(self.name().to_string())),
         }
     }
 
-    fn eq(self, other:Elem<'a>) -> Elem<'a> {
+    /// Works on fractions widened to `i128`, where a product of two `isize`
+    /// values can't overflow, then reduces the result.
+    fn apply_fraction<'a>(self, (an, ad): (i128, i128), (bn, bd): (i128, i128)) -> Result<Atom<'a>, EvalError> {
+        let (num, den) = match self {
+            Arith::Add => ((an * bd).checked_add(bn * ad), ad * bd),
+            Arith::Sub => ((an * bd).checked_sub(bn * ad), ad * bd),
+            Arith::Mul => (Some(an * bn), ad * bd),
+            Arith::Div if bn == 0 => return Err(EvalError::InvalidArgument(self.name().to_string())),
+            Arith::Div => (Some(an * bd), ad * bn),
+        };
+        num.and_then(|num| fraction(num, den)).ok_or_else(|| EvalError::Overflow(self.name().to_string()))
+    }
+
+    /// Like `apply_fraction`, but on bignums, so only a ratio whose parts
+    /// don't fit in an `isize` can overflow.
+    #[cfg(feature = "bignum")]
+    fn apply_big_fraction<'a>(self, (an, ad): (BigInt, BigInt), (bn, bd): (BigInt, BigInt)) -> Result<Atom<'a>, EvalError> {
+        let (num, den) = match self {
+            Arith::Add => (an.mul(&bd).add(&bn.mul(&ad)), ad.mul(&bd)),
+            Arith::Sub => (an.mul(&bd).add(&bn.mul(&ad).neg()), ad.mul(&bd)),
+            Arith::Mul => (an.mul(&bn), ad.mul(&bd)),
+            Arith::Div if bn.is_zero() => return Err(EvalError::InvalidArgument(self.name().to_string())),
+            Arith::Div => (an.mul(&bd), ad.mul(&bn)),
+        };
+        big_fraction(num, den).ok_or_else(|| EvalError::Overflow(self.name().to_string()))
+    }
+}
+
+/// `num/den` in lowest terms with a positive denominator, as a plain number
+/// if it's whole, or `None` if either part doesn't fit in an `isize`.
+fn fraction<'a>(num: i128, den: i128) -> Option<Atom<'a>> {
+    let (mut a, mut b) = (num.unsigned_abs(), den.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let divisor = a as i128 * den.signum();
+    let (num, den) = (isize::try_from(num / divisor).ok()?, isize::try_from(den / divisor).ok()?);
+    Some(if den == 1 { Atom::Number(num) } else { Atom::Ratio { num, den } })
+}
+
+/// Like `fraction`, but a whole result too large for an `isize` becomes a
+/// bignum. `den` must be nonzero.
+#[cfg(feature = "bignum")]
+fn big_fraction<'a>(num: BigInt, den: BigInt) -> Option<Atom<'a>> {
+    let divisor = num.gcd(&den);
+    let divisor = if den.is_negative() { divisor.neg() } else { divisor };
+    let (num, den) = (num.divmod(&divisor)?.0, den.divmod(&divisor)?.0);
+    if den == BigInt::from(1) {
+        return Some(num.to_isize().map_or(Atom::BigInt(num), Atom::Number))
+    }
+    Some(Atom::Ratio { num: num.to_isize()?, den: den.to_isize()? })
+}
+
+impl Atom<'_> {
+    /// A copy that borrows nothing, with symbols interned.
+    fn into_owned<'b>(self) -> Atom<'b> {
         match self {
-            Elem::Atom(a) | Elem::Single(a) => match other {
-                Elem::Atom(b) | Elem::Single(b) => if a == b {
-                    Elem::Single(Atom::Symbol("t")) 
-                } else {
-                    Elem::List(vec![])
-                }
-                _ => Elem::List(vec![]),
-            },
-            _ => Elem::List(vec![]),
+            Atom::Symbol(name) => Atom::Symbol(intern(name)),
+            Atom::String(value) => Atom::String(Cow::Owned(value.into_owned())),
+            Atom::Number(value) => Atom::Number(value),
+            Atom::Char(value) => Atom::Char(value),
+            Atom::Ratio { num, den } => Atom::Ratio { num, den },
+            #[cfg(feature = "bignum")]
+            Atom::BigInt(value) => Atom::BigInt(value),
         }
     }
 
-    fn ne(self, other:Elem<'a>) -> Elem<'a> {
+    fn is_numeric(&self) -> bool {
         match self {
-            Elem::Atom(a) | Elem::Single(a) => match other {
-                Elem::Atom(b) | Elem::Single(b) => if a != b {
-                    Elem::Single(Atom::Symbol("t")) 
-                } else {
-                    Elem::List(vec![])
-                }
-                _ => Elem::List(vec![]),
-            },
-            _ => Elem::List(vec![]),
+            Atom::Number(_) | Atom::Ratio { .. } => true,
+            #[cfg(feature = "bignum")]
+            Atom::BigInt(_) => true,
+            _ => false
         }
     }
 
-    fn compare(self, other:Elem<'a>, order:Ordering) -> Elem<'a> {
-        match self {
-            Elem::Atom(Atom::Number(a)) | Elem::Single(Atom::Number(a)) => match other {
-                Elem::Atom(Atom::Number(b)) | Elem::Single(Atom::Number(b)) => if a.cmp(&b) == order {
-                    Elem::Single(Atom::Symbol("t")) 
-                } else {
-                    Elem::List(vec![])
-                }
-                _ => Elem::List(vec![]),
-            },
-            _ => Elem::List(vec![]),
+    /// A number or ratio as a numerator and denominator.
+    fn as_fraction(&self) -> Option<(i128, i128)> {
+        match *self {
+            Atom::Number(value) => Some((value as i128, 1)),
+            Atom::Ratio { num, den } => Some((num as i128, den as i128)),
+            _ => None
         }
     }
 
-    fn ifelse(self, t:Elem<'a>, f:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> Elem<'a> {
+    /// Any number as a numerator and positive denominator.
+    #[cfg(feature = "bignum")]
+    fn to_big_fraction(&self) -> Option<(BigInt, BigInt)> {
         match self {
-            Elem::Atom(_) | Elem::Single(_) => t.eval(env),
-            _ => f.eval(env),
+            Atom::Number(value) => Some((BigInt::from(*value), BigInt::from(1))),
+            Atom::Ratio { num, den } => Some((BigInt::from(*num), BigInt::from(*den))),
+            Atom::BigInt(value) => Some((value.clone(), BigInt::from(1))),
+            _ => None
         }
     }
 
-    fn cond(self, items:Vec<Elem<'a>>, env: &mut HashMap<&'a str,Elem<'a>>) -> Elem<'a> {
-        let mut first=true;
-        for item in items {
-            if first {
-                first=false;
-                continue;
-            }
-            match item {
-                Elem::List(pair) => match pair[0].clone().eval(env) {
-                    Elem::Atom(_) | Elem::Single(_) => return pair[1].clone().eval(env),
-                    _ => {},
+    /// Orders two numbers by value, or gives `None` if either isn't one.
+    fn cmp_numeric(&self, other: &Atom<'_>) -> Option<Ordering> {
+        if let (Some((an, ad)), Some((bn, bd))) = (self.as_fraction(), other.as_fraction()) {
+            return Some((an * bd).cmp(&(bn * ad)))
+        }
+        #[cfg(feature = "bignum")]
+        if let (Some((an, ad)), Some((bn, bd))) = (self.to_big_fraction(), other.to_big_fraction()) {
+            return Some(an.mul(&bd).cmp(&bn.mul(&ad)))
+        }
+        None
+    }
+}
+
+fn gcd(a: isize, b: isize) -> Option<isize> {
+    let (mut a, mut b) = (a.checked_abs()?, b.checked_abs()?);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    Some(a)
+}
+
+fn lcm(a: isize, b: isize) -> Option<isize> {
+    if a == 0 || b == 0 {
+        return Some(0);
+    }
+    (a / gcd(a, b)?).checked_mul(b)?.checked_abs()
+}
+
+fn fold_numbers<'a>(items: &[Elem<'a>], numbers: Vec<isize>, f: fn(isize, isize) -> Option<isize>) -> EvalResult<'a> {
+    if numbers.len() < 2 {
+        return Err(EvalError::MissingArgument(items[0].to_string()));
+    }
+    let mut acc = numbers[0];
+    for value in &numbers[1..] {
+        acc = f(acc, *value).ok_or_else(|| EvalError::Overflow(items[0].to_string()))?;
+    }
+    Ok(Elem::Atom(Atom::Number(acc)))
+}
+
+/// The most elements `realize` will pull from a lazy sequence before giving
+/// up on it as endless, the most `range` will produce, and the most bytes
+/// `repeat-str` and `zero-pad` will build.
+pub const REALIZE_LIMIT: usize = 1_000_000;
+
+/// Options controlling how programs are evaluated on the current thread.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct EvalConfig {
+    /// Lowercase symbol names before looking them up, binding them or
+    /// dispatching builtins on them, so `ADD` and `add` are the same.
+    pub case_insensitive: bool,
+}
+
+thread_local! {
+    static RNG_STATE: Cell<u64> = const { Cell::new(0x2545_f491_4f6c_dd1d) };
+    static CONFIG: Cell<EvalConfig> = Cell::new(EvalConfig::default());
+    static INTERNED_SYMBOLS: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+    static RECORD_TYPES: RefCell<HashMap<&'static str, Vec<&'static str>>> = RefCell::new(HashMap::new());
+    static REGISTRY: RefCell<HashMap<&'static str, BuiltinFn>> = RefCell::new(default_registry());
+    /// Builtins still evaluated by `eval_nested`, until `register_builtin`
+    /// replaces them.
+    static NESTED_BUILTINS: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::from(["add", "list"]));
+    static OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+    static DEADLINE: Cell<Option<(Instant, Duration)>> = const { Cell::new(None) };
+    static STEPS: Cell<u32> = const { Cell::new(0) };
+}
+
+/// How many calls are evaluated between looks at the clock while a timeout
+/// is set.
+const TIMEOUT_CHECK_STEPS: u32 = 256;
+
+/// Aborts evaluation on the current thread with `EvalError::Timeout` once
+/// `limit` has passed from now, or lifts the limit when given `None`.
+pub fn set_timeout(limit: Option<Duration>) {
+    DEADLINE.with(|deadline| deadline.set(limit.map(|limit| (Instant::now() + limit, limit))));
+}
+
+fn check_timeout() -> Result<(), EvalError> {
+    let Some((deadline, limit)) = DEADLINE.with(Cell::get) else {
+        return Ok(())
+    };
+    let steps = STEPS.with(|steps| {
+        steps.set(steps.get().wrapping_add(1));
+        steps.get()
+    });
+    if steps.is_multiple_of(TIMEOUT_CHECK_STEPS) && Instant::now() >= deadline {
+        Err(EvalError::Timeout(limit))
+    } else {
+        Ok(())
+    }
+}
+
+/// Collects output written by scripts on the current thread, along with the
+/// results printed by `eval_and_print`, into a buffer instead of printing
+/// it, until called again with `false`.
+pub fn capture_output(capture: bool) {
+    OUTPUT.with(|output| *output.borrow_mut() = capture.then(String::new));
+}
+
+/// Takes everything captured since `capture_output` was turned on or the
+/// buffer was last taken.
+pub fn take_output() -> String {
+    OUTPUT.with(|output| output.borrow_mut().as_mut().map(std::mem::take).unwrap_or_default())
+}
+
+fn write_line(line: &str) {
+    OUTPUT.with(|output| match output.borrow_mut().as_mut() {
+        Some(buffer) => {
+            buffer.push_str(line);
+            buffer.push('\n');
+        },
+        None => println!("{}",line)
+    });
+}
+
+/// Sets the evaluation options used on the current thread.
+pub fn set_config(config: EvalConfig) {
+    CONFIG.with(|current| current.set(config));
+}
+
+/// Lowercases `name` when evaluation is case-insensitive. Folded names are
+/// interned, so each distinct symbol is only allocated once.
+fn fold_case(name: &str) -> &str {
+    if !CONFIG.with(Cell::get).case_insensitive || !name.chars().any(char::is_uppercase) {
+        return name;
+    }
+    intern(&name.to_lowercase())
+}
+
+/// How many levels of a form `eval_nested` shows when reporting an error.
+const ERROR_FORM_DEPTH: usize = 8;
+
+/// Drops the elements of a form one level at a time, so a deeply nested one
+/// doesn't overflow the stack the way a recursive drop would.
+fn dismantle(items: Vec<Elem<'_>>) {
+    let mut pending = vec![items];
+    while let Some(items) = pending.pop() {
+        for item in items {
+            if let Elem::Call(inner) | Elem::List(inner) = item {
+                pending.push(inner);
+            }
+        }
+    }
+}
+
+/// Gives `name` a static lifetime, allocating each distinct name only once.
+fn intern(name: &str) -> &'static str {
+    INTERNED_SYMBOLS.with(|symbols| {
+        let mut symbols = symbols.borrow_mut();
+        match symbols.get(name) {
+            Some(symbol) => *symbol,
+            None => {
+                let symbol: &'static str = Box::leak(name.into());
+                symbols.insert(symbol);
+                symbol
+            }
+        }
+    })
+}
+
+/// Constructs a record when `name` is a type defined with `deftype`, or reads
+/// a field when it's one of the type's `type-field` accessors. Records are
+/// lists tagged with their type name.
+fn record_call<'a>(name: &str, items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>) -> Option<EvalResult<'a>> {
+    let (tag, field, count) = RECORD_TYPES.with(|types| {
+        let types = types.borrow();
+        match types.get_key_value(name) {
+            Some((tag, fields)) => Some((*tag, None, fields.len())),
+            None => types.iter().find_map(|(tag, fields)| {
+                let field = name.strip_prefix(tag)?.strip_prefix('-')?;
+                Some((*tag, Some(fields.iter().position(|name| *name == field)?), fields.len()))
+            })
+        }
+    })?;
+    let tag = Elem::Atom(Atom::Symbol(tag));
+    Some(match field {
+        None => (1..=count).map(|index| eval_arg(items,index,env)).collect::<Result<Vec<_>,_>>().map(|fields| {
+            Elem::List(std::iter::once(tag).chain(fields).collect())
+        }),
+        Some(index) => match eval_arg(items,1,env) {
+            Ok(Elem::List(record)) if record.len() == count + 1 && record[0] == tag => Ok(record[index + 1].clone()),
+            Ok(_) => Err(EvalError::InvalidArgument(name.to_string())),
+            Err(error) => Err(error)
+        }
+    })
+}
+
+/// Seeds the random number generator used by `rand` on the current thread,
+/// so scripts can be replayed with the same sequence of values.
+pub fn seed_rng(seed: u64) {
+    RNG_STATE.with(|state| state.set(seed));
+}
+
+/// SplitMix64, which is small and good enough for scripting.
+fn next_random() -> u64 {
+    RNG_STATE.with(|state| {
+        let next = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(next);
+        let mut z = next;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+
+fn random_below(bound: usize) -> usize {
+    (next_random() % bound as u64) as usize
+}
+
+impl<'a> Elem<'a> {
+    /// A copy that borrows nothing, with symbols interned. Delays are copied
+    /// rather than shared.
+    fn into_owned<'b>(self) -> Elem<'b> {
+        let owned = |items: Vec<Elem<'a>>| items.into_iter().map(Elem::into_owned).collect();
+        match self {
+            Elem::Atom(atom) => Elem::Atom(atom.into_owned()),
+            Elem::Single(atom) => Elem::Single(atom.into_owned()),
+            Elem::Call(items) => Elem::Call(owned(items)),
+            Elem::List(items) => Elem::List(owned(items)),
+            Elem::Lazy(Seq::Range(start, end)) => Elem::Lazy(Seq::Range(start, end)),
+            Elem::Lazy(Seq::Map(f, seq)) => Elem::Lazy(Seq::Map(Box::new(f.into_owned()), Box::new(seq.into_owned()))),
+            Elem::Lazy(Seq::Filter(f, seq)) => Elem::Lazy(Seq::Filter(Box::new(f.into_owned()), Box::new(seq.into_owned()))),
+            Elem::Delay(delayed) => Elem::Delay(Rc::new(RefCell::new(match delayed.borrow().clone() {
+                Delayed::Pending(value) => Delayed::Pending(value.into_owned()),
+                Delayed::Forced(value) => Delayed::Forced(value.into_owned()),
+            }))),
+        }
+    }
+
+    fn eval(self, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        match self {
+            Elem::Atom(_) => Ok(self.eval_atom(env)),
+            Elem::List(_) | Elem::Lazy(_) | Elem::Delay(_) => Ok(self),
+            Elem::Call(_) if self.is_nested_builtin() => self.eval_nested(env),
+            Elem::Call(_) => self.eval_call(env),
+            Elem::Single(value) => Ok(Elem::Atom(value))
+        }
+    }
+
+    fn is_nested_builtin(&self) -> bool {
+        match self {
+            Elem::Call(items) => match items.first() {
+                Some(Elem::Atom(Atom::Symbol(name @ ("add" | "list")))) => NESTED_BUILTINS.with(|nested| nested.borrow().contains(name)),
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    /// Evaluates `add` and `list` calls with an explicit stack of pending
+    /// argument lists, so deeply nested chains of them don't recurse in Rust.
+    fn eval_nested(self, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let mut stack = Vec::new();
+        let mut value = None;
+        let mut next = Some(self);
+        let result = loop {
+            if let Some(Elem::Call(items)) = next.take() {
+                stack.push((items, 1, Vec::new()));
+                if let Err(error) = check_timeout() {
+                    break Err(error);
+                }
+            }
+            let (items, index, done) = stack.last_mut().unwrap();
+            if let Some(value) = value.take() {
+                done.push(value);
+            }
+            if let Some(arg) = items.get_mut(*index) {
+                *index += 1;
+                // A nested call is moved out while it's evaluated and put back
+                // afterwards, so the whole form is there to report on error.
+                if arg.is_nested_builtin() {
+                    next = Some(std::mem::replace(arg, Elem::List(vec![])));
+                } else {
+                    match arg.clone().eval(env) {
+                        Ok(result) => value = Some(result),
+                        Err(error) => break Err(error),
+                    }
+                }
+                continue;
+            }
+            let result = match items.first() {
+                Some(Elem::Atom(Atom::Symbol("add"))) => {
+                    let mut sum = Ok(Atom::Number(0));
+                    for item in done.iter() {
+                        if let Elem::Atom(addend) = item {
+                            if addend.is_numeric() {
+                                sum = sum.and_then(|sum| Arith::Add.apply(&sum, addend));
+                            }
+                        }
+                    }
+                    match sum {
+                        Ok(sum) => Elem::Atom(sum),
+                        Err(error) => {
+                            let form = Elem::Call(items.iter().map(|item| item.truncated(ERROR_FORM_DEPTH)).collect());
+                            break Err(error.in_form(&form));
+                        }
+                    }
+                },
+                _ => Elem::List(std::mem::take(done)),
+            };
+            let (items, _, _) = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some((parent, index, _)) => parent[*index - 1] = Elem::Call(items),
+                None => {
+                    dismantle(items);
+                    break Ok(result);
+                }
+            }
+            value = Some(result);
+        };
+        for (items, _, _) in stack {
+            dismantle(items);
+        }
+        result
+    }
+
+    /// A copy with calls and lists more than `depth` levels down replaced by
+    /// `...`, for showing a form of any size in an error.
+    fn truncated(&self, depth: usize) -> Elem<'a> {
+        match self {
+            Elem::Call(_) | Elem::List(_) if depth == 0 => Elem::Atom(Atom::Symbol("...")),
+            Elem::Call(items) => Elem::Call(items.iter().map(|item| item.truncated(depth - 1)).collect()),
+            Elem::List(items) => Elem::List(items.iter().map(|item| item.truncated(depth - 1)).collect()),
+            _ => self.clone(),
+        }
+    }
+
+    fn eval_atom(self, env: &mut HashMap<&'a str,Elem<'a>>) -> Elem<'a> {
+        if let Elem::Atom(Atom::Symbol(name)) = self {
+            let name = fold_case(name);
+            if env.contains_key(name) {
+                env[name].clone()
+            } else {
+                self
+            }
+        } else {
+            self
+        }
+    }
+
+    fn eval_call(self, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        if let Elem::Call(ref items) = self {
+            if items.is_empty() {
+                return Ok(self)
+            }
+            check_timeout()?;
+            if let Elem::Atom(Atom::Symbol(name)) = items[0] {
+                let folded = fold_case(name);
+                if folded != name {
+                    let mut items_m = items.clone();
+                    items_m[0] = Elem::Atom(Atom::Symbol(folded));
+                    return Elem::Call(items_m).eval(env);
+                }
+                if let Some(builtin) = builtin(name) {
+                    return builtin(items, env).map_err(|error| error.in_form(&self));
+                }
+            }
+            match items[0] {
+                Elem::Atom(Atom::Symbol(name)) => {
+                    if env.contains_key(name) {
+                        let mut items_m = items.clone();
+                        items_m[0] = env[name].clone();
+                        Elem::Call(items_m).eval(env)
+                    } else if let Some(result) = record_call(name, items, env) {
+                        result.map_err(|error| error.in_form(&self))
+                    } else {
+                        Ok(self)
+                    }
+                },
+                Elem::Call(ref subitems) => {
+                    match subitems.first() {
+                        Some(Elem::Atom(Atom::Symbol("fun"))) => {
+                            let mut env_m = env.clone();
+                            if let Elem::List(names) = arg(subitems,1).map_err(|error| error.in_form(&self))? {
+                                for (i, name) in names.into_iter().enumerate() {
+                                    if let Elem::Atom(Atom::Symbol(name_a)) = name {
+                                        env_m.insert(fold_case(name_a),eval_arg(items,i+1,env).map_err(|error| error.in_form(&self))?);
+                                    }
+                                }
+                                let body = if subitems.len() > 3 && matches!(subitems[2], Elem::Atom(Atom::String(_))) { 3 } else { 2 };
+                                eval_arg(subitems,body,&mut env_m).map_err(|error| error.in_form(&self))
+                            } else {
+                                Ok(self)
+                            }
+                        },
+                        _ => match items[0].clone().eval(env)? {
+                            head @ (Elem::Call(_) | Elem::Atom(Atom::Symbol(_))) if head.is_function() => {
+                                let mut items_m = items.clone();
+                                items_m[0] = head;
+                                Elem::Call(items_m).eval(env)
+                            },
+                            _ => Ok(self)
+                        }
+                    }
+                }
+                _ => Ok(self)
+            }
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// Whether this value can be called, being a `fun` or naming a builtin.
+    fn is_function(&self) -> bool {
+        match self {
+            Elem::Call(items) => matches!(items.first(), Some(Elem::Atom(Atom::Symbol("fun")))),
+            Elem::Atom(Atom::Symbol(name)) => builtin(name).is_some(),
+            _ => false
+        }
+    }
+
+    fn truth(value:bool) -> Elem<'a> {
+        if value {
+            Elem::Single(Atom::Symbol("t"))
+        } else {
+            Elem::List(vec![])
+        }
+    }
+
+    fn is_nil(&self) -> bool {
+        matches!(self, Elem::Call(items) | Elem::List(items) if items.is_empty())
+    }
+
+    /// Whether printing this value gives source that reads back as the same
+    /// value, which isn't the case for lazy sequences, delays and ratios.
+    fn is_printable(&self) -> bool {
+        match self {
+            Elem::Call(items) | Elem::List(items) => items.iter().all(Elem::is_printable),
+            Elem::Lazy(_) | Elem::Delay(_) | Elem::Atom(Atom::Ratio { .. }) | Elem::Single(Atom::Ratio { .. }) => false,
+            Elem::Atom(Atom::String(value)) | Elem::Single(Atom::String(value)) => !value.contains('"'),
+            _ => true
+        }
+    }
+
+    fn truthy(&self) -> bool {
+        matches!(self, Elem::Atom(_) | Elem::Single(_))
+    }
+
+    fn quote(self) -> Elem<'a> {
+        match self {
+            Elem::Atom(atom) => Elem::Single(atom),
+            _ => self
+        }
+    }
+
+    /// The data a quoted form stands for: calls become lists all the way down,
+    /// and a bare atom becomes a single.
+    fn into_data(self) -> Elem<'a> {
+        match self {
+            Elem::Atom(atom) => Elem::Single(atom),
+            Elem::Call(items) | Elem::List(items) => Elem::List(items.into_iter().map(|item| match item {
+                Elem::Call(_) | Elem::List(_) => item.into_data(),
+                _ => item
+            }).collect()),
+            _ => self
+        }
+    }
+
+    /// Matches `value` against this pattern, collecting the names it binds.
+    /// `_` matches anything, other symbols match anything and bind it, lists
+    /// match lists of the same length item by item, and any other atom
+    /// matches itself.
+    fn destructure(&self, value: &Elem<'a>, bindings: &mut Vec<(&'a str, Elem<'a>)>) -> bool {
+        match self {
+            Elem::Atom(Atom::Symbol("_")) => true,
+            Elem::Atom(Atom::Symbol(name)) => {
+                bindings.push((fold_case(name), value.clone()));
+                true
+            },
+            Elem::List(patterns) => match value {
+                Elem::List(values) if values.len() == patterns.len() => {
+                    patterns.iter().zip(values).all(|(pattern, value)| pattern.destructure(value, bindings))
                 },
-                _ => {}
+                _ => false
+            },
+            _ => self == value
+        }
+    }
+
+    fn apply(self, args:Vec<Elem<'a>>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let mut items = vec![self];
+        items.extend(args.into_iter().map(Elem::quote));
+        Elem::Call(items).eval(env)
+    }
+
+    fn assoc(&self, key:&Elem<'a>) -> Option<usize> {
+        match self {
+            Elem::Call(items) | Elem::List(items) => items.iter().position(|pair| match pair {
+                Elem::Call(pair) | Elem::List(pair) => !pair.is_empty() && pair[0].clone().eq(key.clone()).truthy(),
+                _ => false
+            }),
+            _ => None
+        }
+    }
+
+    /// The value paired with `key` in this association list.
+    fn assoc_value(&self, key:&Elem<'a>) -> Option<Elem<'a>> {
+        let pair = self.as_sequence()?.get(self.assoc(key)?)?;
+        pair.as_sequence()?.get(1).cloned()
+    }
+
+    fn update(self, key:Elem<'a>, f:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let index = match self.assoc(&key) {
+            Some(index) => index,
+            None => return Ok(self)
+        };
+        match self {
+            Elem::Call(mut items) | Elem::List(mut items) => {
+                let value = items[index].clone().cdr().car();
+                items[index] = Elem::List(vec![key, f.apply(vec![value], env)?]);
+                Ok(Elem::List(items))
+            },
+            _ => Ok(self)
+        }
+    }
+
+    fn with_env(self, body:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let mut saved = Vec::new();
+        if let Elem::Call(bindings) | Elem::List(bindings) = self {
+            for binding in bindings {
+                if let Elem::Call(pair) | Elem::List(pair) = binding {
+                    if let Some(Elem::Atom(Atom::Symbol(name))) = pair.first() {
+                        let name = fold_case(name);
+                        let value = pair.get(1).cloned().unwrap_or(Elem::List(vec![]));
+                        saved.push((name, env.insert(name, value)));
+                    }
+                }
+            }
+        }
+        let result = body.eval(env);
+        for (name, old) in saved.into_iter().rev() {
+            match old {
+                Some(old) => env.insert(name, old),
+                None => env.remove(name),
+            };
+        }
+        result
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Elem::Atom(Atom::String(value)) | Elem::Single(Atom::String(value)) => Some(value),
+            _ => None
+        }
+    }
+
+    fn as_number(&self) -> Option<isize> {
+        match self {
+            Elem::Atom(Atom::Number(value)) | Elem::Single(Atom::Number(value)) => Some(*value),
+            _ => None
+        }
+    }
+
+    fn into_items(self) -> Option<Vec<Elem<'a>>> {
+        match self {
+            Elem::Call(items) | Elem::List(items) => Some(items),
+            _ => None
+        }
+    }
+
+    fn take_nth(self, step:Elem<'a>) -> EvalResult<'a> {
+        match (step.as_number(), self.into_items()) {
+            (Some(step), Some(items)) if step > 0 => Ok(Elem::List(items.into_iter().step_by(step as usize).collect())),
+            _ => Err(EvalError::InvalidArgument("take-nth".to_string()))
+        }
+    }
+
+    fn scan(self, f:Elem<'a>, init:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let mut acc = init;
+        let mut steps = vec![acc.clone()];
+        for item in self.into_items().unwrap_or_default() {
+            acc = f.clone().apply(vec![acc, item], env)?;
+            steps.push(acc.clone());
+        }
+        Ok(Elem::List(steps))
+    }
+
+    fn fold(self, f:Elem<'a>, init:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let mut acc = init;
+        for item in self.into_items().unwrap_or_default() {
+            acc = f.clone().apply(vec![acc, item], env)?;
+        }
+        Ok(acc)
+    }
+
+    fn find(self, pred:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        for item in self.into_items().unwrap_or_default() {
+            if pred.clone().apply(vec![item.clone()], env)?.truthy() {
+                return Ok(item);
+            }
+        }
+        Ok(Elem::List(vec![]))
+    }
+
+    fn map(self, f:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        if let Elem::Lazy(_) = self {
+            return Ok(Elem::Lazy(Seq::Map(Box::new(f), Box::new(self))));
+        }
+        let mut mapped = Vec::new();
+        for item in self.into_items().unwrap_or_default() {
+            mapped.push(f.clone().apply(vec![item], env)?);
+        }
+        Ok(Elem::List(mapped))
+    }
+
+    /// Each distinct item with how many times it appears, in the order the
+    /// items first appear.
+    fn frequencies(self) -> Vec<(Elem<'a>, usize)> {
+        let mut counts: Vec<(Elem<'a>, usize)> = Vec::new();
+        for item in self.into_items().unwrap_or_default() {
+            match counts.iter_mut().find(|(seen, _)| *seen == item) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((item, 1)),
+            }
+        }
+        counts
+    }
+
+    fn flatten(self, flat: &mut Vec<Elem<'a>>) {
+        match self {
+            Elem::Call(items) | Elem::List(items) => items.into_iter().for_each(|item| item.flatten(flat)),
+            _ => flat.push(self)
+        }
+    }
+
+    fn keep(self, pred:Elem<'a>, wanted:bool, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let mut kept = Vec::new();
+        for item in self.into_items().unwrap_or_default() {
+            if pred.clone().apply(vec![item.clone()], env)?.truthy() == wanted {
+                kept.push(item);
             }
         }
-        Elem::List(vec![])
+        Ok(Elem::List(kept))
+    }
+
+    fn offset(self, by:isize, name:&str) -> EvalResult<'a> {
+        let shift = |value:Elem<'a>| match value {
+            Elem::Atom(atom) | Elem::Single(atom) if atom.is_numeric() => Arith::Add.apply(&atom, &Atom::Number(by))
+                .map(Elem::Atom)
+                .map_err(|_| EvalError::Overflow(name.to_string())),
+            _ => Err(EvalError::InvalidArgument(name.to_string()))
+        };
+        if let Elem::Atom(_) | Elem::Single(_) = self {
+            return shift(self);
+        }
+        match self.into_items() {
+            Some(items) => items.into_iter().map(shift).collect::<Result<_, _>>().map(Elem::List),
+            None => Err(EvalError::InvalidArgument(name.to_string()))
+        }
+    }
+
+    fn repeat_str(self, count:Elem<'a>) -> EvalResult<'a> {
+        match (self, count.as_number()) {
+            (Elem::Atom(Atom::String(value)), Some(count)) => {
+                let count = count.max(0) as usize;
+                if value.len().checked_mul(count).is_none_or(|len| len > REALIZE_LIMIT) {
+                    return Err(EvalError::LimitExceeded("repeat-str".to_string()));
+                }
+                Ok(Elem::Atom(Atom::String(Cow::Owned(value.repeat(count)))))
+            },
+            _ => Err(EvalError::InvalidArgument("repeat-str".to_string()))
+        }
+    }
+
+    fn trim_with(self, trim:fn(&str) -> &str, name:&str) -> EvalResult<'a> {
+        match self {
+            Elem::Atom(Atom::String(Cow::Borrowed(value))) => Ok(Elem::Atom(Atom::String(Cow::Borrowed(trim(value))))),
+            Elem::Atom(Atom::String(Cow::Owned(value))) => Ok(Elem::Atom(Atom::String(Cow::Owned(trim(&value).to_string())))),
+            _ => Err(EvalError::InvalidArgument(name.to_string()))
+        }
+    }
+
+    fn affix(self, other:Elem<'a>, test:fn(&str, &str) -> bool, name:&str) -> EvalResult<'a> {
+        match (self.as_str(), other.as_str()) {
+            (Some(value), Some(affix)) => Ok(Elem::truth(test(value, affix))),
+            _ => Err(EvalError::InvalidArgument(name.to_string()))
+        }
+    }
+
+    fn split_with(self, split:fn(&str) -> Vec<&str>, name:&str) -> EvalResult<'a> {
+        let parts: Vec<Cow<'a, str>> = match self {
+            Elem::Atom(Atom::String(Cow::Borrowed(value))) => split(value).into_iter().map(Cow::Borrowed).collect(),
+            Elem::Atom(Atom::String(Cow::Owned(value))) => split(&value).into_iter().map(|part| Cow::Owned(part.to_string())).collect(),
+            _ => return Err(EvalError::InvalidArgument(name.to_string()))
+        };
+        Ok(Elem::List(parts.into_iter().map(|part| Elem::Atom(Atom::String(part))).collect()))
+    }
+
+    fn zero_pad(self, width:Elem<'a>) -> EvalResult<'a> {
+        match (self.as_number(), width.as_number()) {
+            (Some(value), Some(width)) => {
+                let width = width.max(0) as usize;
+                if width > REALIZE_LIMIT {
+                    return Err(EvalError::LimitExceeded("zero-pad".to_string()));
+                }
+                // Padded by hand, since format! can't take a width above u16::MAX.
+                let (sign, digits) = (if value < 0 { "-" } else { "" }, value.unsigned_abs().to_string());
+                let zeros = "0".repeat(width.saturating_sub(sign.len() + digits.len()));
+                Ok(Elem::Atom(Atom::String(Cow::Owned(format!("{}{}{}", sign, zeros, digits)))))
+            },
+            _ => Err(EvalError::InvalidArgument("zero-pad".to_string()))
+        }
+    }
+
+    fn clamp(self, lo:Elem<'a>, hi:Elem<'a>) -> EvalResult<'a> {
+        match (self.as_number(), lo.as_number(), hi.as_number()) {
+            (Some(value), Some(lo), Some(hi)) if lo <= hi => Ok(Elem::Atom(Atom::Number(value.clamp(lo, hi)))),
+            _ => Err(EvalError::InvalidArgument("clamp".to_string()))
+        }
+    }
+
+    fn shuffle(self) -> Elem<'a> {
+        let mut items = self.into_items().unwrap_or_default();
+        for i in (1..items.len()).rev() {
+            items.swap(i, random_below(i + 1));
+        }
+        Elem::List(items)
+    }
+
+    fn sample(self, count:Elem<'a>) -> EvalResult<'a> {
+        let count = match count.as_number() {
+            Some(count) if count >= 0 => count as usize,
+            _ => return Err(EvalError::InvalidArgument("sample".to_string()))
+        };
+        let mut items = self.into_items().unwrap_or_default();
+        let count = count.min(items.len());
+        for i in 0..count {
+            let j = i + random_below(items.len() - i);
+            items.swap(i, j);
+        }
+        items.truncate(count);
+        Ok(Elem::List(items))
+    }
+
+    /// Splits a list or lazy sequence into its first element and the rest,
+    /// or `None` once it's empty.
+    fn uncons(self, env: &mut HashMap<&'a str,Elem<'a>>) -> Result<Option<(Elem<'a>, Elem<'a>)>, EvalError> {
+        match self {
+            Elem::Lazy(seq) => seq.uncons(env),
+            Elem::Call(mut items) | Elem::List(mut items) => {
+                if items.is_empty() {
+                    return Ok(None);
+                }
+                let first = items.remove(0);
+                Ok(Some((first, Elem::List(items))))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    fn take(self, count:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let count = match count.as_number() {
+            Some(count) if count >= 0 => count,
+            _ => return Err(EvalError::InvalidArgument("take".to_string()))
+        };
+        let mut taken = Vec::new();
+        let mut rest = self;
+        while (taken.len() as isize) < count {
+            match rest.uncons(env)? {
+                Some((first, next)) => {
+                    taken.push(first);
+                    rest = next;
+                },
+                None => break
+            }
+        }
+        Ok(Elem::List(taken))
+    }
+
+    fn drop(self, count:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let count = match count.as_number() {
+            Some(count) if count >= 0 => count as usize,
+            _ => return Err(EvalError::InvalidArgument("drop".to_string()))
+        };
+        if let Elem::Lazy(_) = self {
+            let mut rest = self;
+            for dropped in 0..count {
+                if dropped == REALIZE_LIMIT {
+                    return Err(EvalError::LimitExceeded("drop".to_string()));
+                }
+                match rest.uncons(env)? {
+                    Some((_, next)) => rest = next,
+                    None => return Ok(Elem::List(vec![]))
+                }
+            }
+            return Ok(rest)
+        }
+        Ok(Elem::List(self.as_sequence().map_or(vec![], |items| items.iter().skip(count).cloned().collect())))
+    }
+
+    fn realize(self, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let mut realized = Vec::new();
+        let mut rest = self;
+        while let Some((first, next)) = rest.uncons(env)? {
+            if realized.len() == REALIZE_LIMIT {
+                return Err(EvalError::LimitExceeded("realize".to_string()));
+            }
+            realized.push(first);
+            rest = next;
+        }
+        Ok(Elem::List(realized))
+    }
+
+    fn force(self, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let cell = match self {
+            Elem::Delay(cell) => cell,
+            _ => return Ok(self)
+        };
+        let body = match &*cell.borrow() {
+            Delayed::Forced(value) => return Ok(value.clone()),
+            Delayed::Pending(body) => body.clone()
+        };
+        let value = body.eval(env)?;
+        *cell.borrow_mut() = Delayed::Forced(value.clone());
+        Ok(value)
+    }
+
+    /// Rewrites a step like `(f a b)` into `(f value a b)`, or `f` into
+    /// `(f value)`.
+    fn thread(self, value:Elem<'a>) -> Elem<'a> {
+        match self {
+            Elem::Call(mut items) if !items.is_empty() => {
+                items.insert(1, value.quote());
+                Elem::Call(items)
+            },
+            _ => Elem::Call(vec![self, value.quote()])
+        }
+    }
+
+    fn join(self) -> EvalResult<'a> {
+        let mut joined = String::new();
+        for item in self.into_items().unwrap_or_default() {
+            match item {
+                Elem::Atom(Atom::Char(value)) => joined.push(value),
+                Elem::Atom(Atom::String(value)) => joined.push_str(&value),
+                _ => return Err(EvalError::InvalidArgument("join".to_string()))
+            }
+        }
+        Ok(Elem::Atom(Atom::String(Cow::Owned(joined))))
+    }
+
+    fn cons(self, other:Elem<'a>) -> Elem<'a> {
+        match other {
+            Elem::Call(mut items) | Elem::List(mut items) => {
+                items.insert(0, self);
+                Elem::List(items)
+            },
+            _ => Elem::List(vec![self, other])  
+        }
+    }
+
+    /// Appends `other` to `self`, splicing in its elements when it's a list.
+    fn rcons(self, other:Elem<'a>) -> Elem<'a> {
+        match self {
+            Elem::Call(mut items) | Elem::List(mut items) => {
+                match other {
+                    Elem::Call(others) | Elem::List(others) => items.extend(others),
+                    other => items.push(other),
+                }
+                Elem::List(items)
+            },
+            _ => Elem::List(vec![self, other])  
+        }
+    }
+
+    /// The items of a list or a call, which are both sequences.
+    fn as_sequence(&self) -> Option<&[Elem<'a>]> {
+        match self {
+            Elem::Call(items) | Elem::List(items) => Some(items),
+            _ => None
+        }
+    }
+
+    fn car(self) -> Elem<'a> {
+        match self.as_sequence() {
+            Some(items) => items.first().cloned().unwrap_or(Elem::List(vec![])),
+            None => self
+        }
+    }
+
+    fn cdr(self) -> Elem<'a> {
+        Elem::List(self.as_sequence().map_or(vec![], |items| items.iter().skip(1).cloned().collect()))
+    }
+
+    fn atom(self) -> Elem<'a> {
+        match self {
+            Elem::Atom(_) | Elem::Single(_) => Elem::Single(Atom::Symbol("t")),
+            _ => Elem::List(vec![])
+        }
+    }
+
+    fn not(self) -> Elem<'a> {
+        match self {
+            Elem::List(items) | Elem::Call(items) => if items.is_empty() {
+                Elem::Single(Atom::Symbol("t"))
+            } else {
+                Elem::List(vec![])
+            }
+            _ => Elem::List(vec![])
+        }
+    }
+
+    fn eq(self, other:Elem<'a>) -> Elem<'a> {
+        match self {
+            Elem::Atom(a) | Elem::Single(a) => match other {
+                Elem::Atom(b) | Elem::Single(b) => if a == b {
+                    Elem::Single(Atom::Symbol("t")) 
+                } else {
+                    Elem::List(vec![])
+                }
+                _ => Elem::List(vec![]),
+            },
+            _ => Elem::List(vec![]),
+        }
+    }
+
+    /// Like `eq`, but lists compare equal too when their elements do, all the
+    /// way down.
+    fn equal(self, other:Elem<'a>) -> Elem<'a> {
+        Elem::truth(self == other)
+    }
+
+    fn ne(self, other:Elem<'a>) -> Elem<'a> {
+        match self {
+            Elem::Atom(a) | Elem::Single(a) => match other {
+                Elem::Atom(b) | Elem::Single(b) => if a != b {
+                    Elem::Single(Atom::Symbol("t")) 
+                } else {
+                    Elem::List(vec![])
+                }
+                _ => Elem::List(vec![]),
+            },
+            _ => Elem::List(vec![]),
+        }
+    }
+
+    fn compare(self, other:Elem<'a>, order:Ordering) -> Elem<'a> {
+        match self {
+            Elem::Atom(Atom::Char(a)) | Elem::Single(Atom::Char(a)) => match other {
+                Elem::Atom(Atom::Char(b)) | Elem::Single(Atom::Char(b)) => Elem::truth(a.cmp(&b) == order),
+                _ => Elem::List(vec![]),
+            },
+            Elem::Atom(Atom::String(a)) | Elem::Single(Atom::String(a)) => match other {
+                Elem::Atom(Atom::String(b)) | Elem::Single(Atom::String(b)) => Elem::truth(a.cmp(&b) == order),
+                _ => Elem::List(vec![]),
+            },
+            Elem::Atom(Atom::Symbol(a)) | Elem::Single(Atom::Symbol(a)) => match other {
+                Elem::Atom(Atom::Symbol(b)) | Elem::Single(Atom::Symbol(b)) => Elem::truth(a.cmp(b) == order),
+                _ => Elem::List(vec![]),
+            },
+            Elem::Atom(a) | Elem::Single(a) if a.is_numeric() => match other {
+                Elem::Atom(b) | Elem::Single(b) => match a.cmp_numeric(&b) {
+                    Some(ordering) => Elem::truth(ordering == order),
+                    None => Elem::List(vec![]),
+                },
+                _ => Elem::List(vec![]),
+            },
+            _ => Elem::List(vec![]),
+        }
+    }
+
+    fn ifelse(self, t:Elem<'a>, f:Elem<'a>, env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        match self {
+            Elem::Atom(_) | Elem::Single(_) => t.eval(env),
+            _ => f.eval(env),
+        }
+    }
+
+    fn cond(items:&[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+        let mut first=true;
+        for item in items {
+            if first {
+                first=false;
+                continue;
+            }
+            if let Elem::List(pair) = item {
+                match pair[0].clone().eval(env)? {
+                    Elem::Atom(_) | Elem::Single(_) => return pair[1].clone().eval(env),
+                    _ => {},
+                }
+            }
+        }
+        Ok(Elem::List(vec![]))
+    }
+}
+
+impl<'a> Seq<'a> {
+    fn uncons(self, env: &mut HashMap<&'a str,Elem<'a>>) -> Result<Option<(Elem<'a>, Elem<'a>)>, EvalError> {
+        match self {
+            Seq::Range(start, end) => {
+                if end.is_some_and(|end| start >= end) {
+                    return Ok(None);
+                }
+                match start.checked_add(1) {
+                    Some(next) => Ok(Some((Elem::Atom(Atom::Number(start)), Elem::Lazy(Seq::Range(next, end))))),
+                    None => Err(EvalError::Overflow("range".to_string()))
+                }
+            },
+            Seq::Map(f, source) => match source.uncons(env)? {
+                Some((first, rest)) => Ok(Some((f.clone().apply(vec![first], env)?, Elem::Lazy(Seq::Map(f, Box::new(rest)))))),
+                None => Ok(None)
+            },
+            Seq::Filter(pred, source) => {
+                let mut source = *source;
+                while let Some((first, rest)) = source.uncons(env)? {
+                    if pred.clone().apply(vec![first.clone()], env)?.truthy() {
+                        return Ok(Some((first, Elem::Lazy(Seq::Filter(pred, Box::new(rest))))));
+                    }
+                    source = rest;
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Parses and evaluates the next form in `input`, printing its value.
+///
+/// Returns the input remaining after the form alongside the evaluation
+/// result, so a caller can report a failed form and carry on with the next.
+pub fn eval_and_print<'a>(input:&'a str,env:&mut HashMap<&'a str,Elem<'a>>) -> Result<(&'a str,EvalResult<'a>),Box<dyn Error + 'a>>{
+    let (input, elem) = expr(input)?;
+    let result = elem.eval(env);
+    if let Ok(value) = &result {
+        write_line(&value.to_string());
+    }
+    Ok((input,result))
+}
+
+/// Evaluates every form in `input`, collecting one result per form.
+///
+/// A form that fails to evaluate doesn't stop the ones after it; a parse
+/// error does, since there's no telling where the broken form ends.
+pub fn eval_all<'a>(input:&'a str,env:&mut HashMap<&'a str,Elem<'a>>) -> Vec<EvalResult<'a>> {
+    let mut input = input;
+    let mut results = Vec::new();
+    while !input.trim().is_empty() {
+        match expr(input) {
+            Ok((rest, elem)) => {
+                results.push(elem.eval(env));
+                input = rest;
+            },
+            Err(error) => {
+                results.push(Err(EvalError::Parse(error.to_string())));
+                break;
+            }
+        }
+    }
+    results
+}
+
+/// Writes the bindings in `env` to the file at `path`, one `(let name value)`
+/// form per line. Bindings whose values can't be read back, such as lazy
+/// sequences, delays and strings containing `"`, are left out.
+pub fn save_env(env:&HashMap<&str,Elem<'_>>,path:&str) -> Result<(),EvalError> {
+    let mut bindings: Vec<_> = env.iter().filter(|(_, value)| value.is_printable()).collect();
+    bindings.sort_by_key(|(name, _)| **name);
+    let mut out = String::new();
+    for (name, value) in bindings {
+        out.push_str(&format!("(let {} {})\n", Atom::Symbol(name), value));
+    }
+    std::fs::write(path, out).map_err(|error| EvalError::Io(error.to_string()))
+}
+
+/// Reads bindings saved by `save_env` from the file at `path` into `env`.
+///
+/// The loaded values are copied out of the file's contents, with their
+/// symbols interned, so nothing borrows from the file once it's read.
+pub fn load_env<'a>(path:&str,env:&mut HashMap<&'a str,Elem<'a>>) -> Result<(),EvalError> {
+    let source = std::fs::read_to_string(path).map_err(|error| EvalError::Io(error.to_string()))?;
+    let mut input = source.as_str();
+    while !input.trim().is_empty() {
+        let (rest, elem) = expr(input).map_err(|error| EvalError::Parse(error.to_string()))?;
+        match elem {
+            Elem::Call(items) if items.len() == 3 && matches!(items[0], Elem::Atom(Atom::Symbol("let"))) => {
+                if let Elem::Atom(Atom::Symbol(name)) = items[1] {
+                    env.insert(intern(name), items[2].clone().into_owned());
+                    input = rest;
+                    continue;
+                }
+            },
+            _ => {}
+        }
+        return Err(EvalError::Parse(format!("expected a let form in {}", path)));
+    }
+    Ok(())
+}
+
+/// Parses a single form, so `"(add 1 2)".parse::<Elem>()` builds the call
+/// without evaluating it.
+///
+/// The result owns its strings and interns its symbols, so it doesn't borrow
+/// from the input.
+impl std::str::FromStr for Elem<'static> {
+    type Err = EvalError;
+
+    fn from_str(input: &str) -> Result<Elem<'static>, EvalError> {
+        match expr(input) {
+            Ok((rest, elem)) if rest.trim().is_empty() => Ok(elem.into_owned()),
+            Ok((rest, _)) => Err(EvalError::Parse(format!("unexpected input after form: {}", rest.trim()))),
+            Err(error) => Err(EvalError::Parse(error.to_string())),
+        }
+    }
+}
+
+/// Forms wider than this are broken across lines by `format_source`.
+const FORMAT_WIDTH: usize = 60;
+
+fn format_elem(elem: &Elem<'_>, indent: usize, out: &mut String) {
+    let flat = elem.to_string();
+    let (open, close, items) = match elem {
+        Elem::Call(items) if !items.is_empty() => ('(', ')', items),
+        Elem::List(items) if !items.is_empty() => ('[', ']', items),
+        _ => {
+            out.push_str(&flat);
+            return;
+        }
+    };
+    if indent + flat.len() <= FORMAT_WIDTH {
+        out.push_str(&flat);
+        return;
+    }
+    out.push(open);
+    format_elem(&items[0], indent + 1, out);
+    for item in &items[1..] {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent + 2));
+        format_elem(item, indent + 2, out);
+    }
+    out.push(close);
+}
+
+/// Parses `input` and prints it back out in canonical form: one space between
+/// elements, one top-level form per line, and forms too wide for a line split
+/// with each argument on its own indented line.
+///
+/// The language has no comments yet, so there are none to preserve.
+pub fn format_source(input:&str) -> Result<String,EvalError> {
+    let mut input = input;
+    let mut out = String::new();
+    while !input.trim().is_empty() {
+        let (rest, elem) = expr(input).map_err(|error| EvalError::Parse(error.to_string()))?;
+        format_elem(&elem, 0, &mut out);
+        out.push('\n');
+        input = rest;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_run<'a>(input: &'a str, env: &mut HashMap<&'a str,Elem<'a>>) -> Result<String, EvalError> {
+        let mut input = input;
+        let mut last = Elem::List(vec![]);
+        while !input.trim().is_empty() {
+            let (rest, elem) = expr(input).unwrap();
+            last = elem.eval(env).map_err(|error| match error {
+                EvalError::InForm(_, cause) => *cause,
+                error => error,
+            })?;
+            input = rest;
+        }
+        Ok(last.to_string())
+    }
+
+    fn run<'a>(input: &'a str, env: &mut HashMap<&'a str,Elem<'a>>) -> String {
+        try_run(input, env).unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        let result = expr("(cons #A [B C :D \"EFG\" 1 2 3])");
+        println!("{}",result.unwrap().1.eval(&mut HashMap::new()).unwrap());
+    }
+
+    #[test]
+    fn things() {
+        let result = expr("(value (head [:KEY #VALUE]))");
+        println!("{}",result.unwrap().1.eval(&mut HashMap::new()).unwrap());
+    }
+
+    #[test]
+    fn numbers() {
+        let result = expr("(let second (car (cdr x)))");
+        let mut env = HashMap::new();
+        println!("{}",result.unwrap().1.eval(&mut env).unwrap());
+        let result2 = expr("(second A B C)");
+        println!("{}",result2.unwrap().1.eval(&mut env).unwrap());
+    }
+
+    #[test]
+    fn cond_test() {
+        let result = expr("(cond [(le (add 3 2) 5) \"3 + 2 <= 5\"] [T \"Catch-all\"])");
+        println!("{}",result.unwrap().1.eval(&mut HashMap::new()).unwrap());
+    }
+
+    #[test]
+    fn fun_test() {
+        let result = expr("(let tri (fun [n] (if (gt n 0) (add n (tri (add n -1))) (0))))");
+        let mut env = HashMap::new();
+        println!("{}",result.unwrap().1.eval(&mut env).unwrap());
+        let result2 = expr("(tri 5)");
+        println!("{}",result2.unwrap().1.eval(&mut env).unwrap());
+    }
+
+    #[test]
+    fn update_test() {
+        let mut env = HashMap::new();
+        run("(let ages [[ann 30] [bob 41]])", &mut env);
+        assert_eq!(run("(update #bob ages (fun [n] (add n 1)))", &mut env), "[[ann 30] [bob 42]]");
+        assert_eq!(run("(update #ann ages head)", &mut env), "[[ann 30] [bob 41]]");
+    }
+
+    #[test]
+    fn update_missing_key() {
+        let mut env = HashMap::new();
+        run("(let ages [[ann 30] [bob 41]])", &mut env);
+        assert_eq!(run("(update #cy ages (fun [n] (add n 1)))", &mut env), "[[ann 30] [bob 41]]");
+    }
+
+    #[test]
+    fn nested_add() {
+        let mut elem = Elem::Atom(Atom::Number(0));
+        for _ in 0..100000 {
+            elem = Elem::Call(vec![Elem::Atom(Atom::Symbol("add")), Elem::Atom(Atom::Number(1)), elem]);
+        }
+        assert_eq!(elem.eval(&mut HashMap::new()).unwrap().to_string(), "100000");
+        assert_eq!(run("(list 1 (add 2 (add 3 4)) (list 5 (list)))", &mut HashMap::new()), "[1 9 [5 []]]");
+        let mut elem = Elem::Atom(Atom::Number(0));
+        for _ in 0..100000 {
+            elem = Elem::Call(vec![Elem::Atom(Atom::Symbol("list")), elem]);
+        }
+        set_timeout(Some(Duration::ZERO));
+        let result = elem.eval(&mut HashMap::new());
+        set_timeout(None);
+        assert!(matches!(result, Err(EvalError::Timeout(_))));
+    }
+
+    #[cfg(not(feature = "bignum"))]
+    #[test]
+    fn nested_add_error() {
+        let mut env = HashMap::new();
+        let error = (expr("(list 1 (add 2 (add 9223372036854775807 1)))").unwrap().1).eval(&mut env).unwrap_err();
+        assert!(matches!(error.root_cause(), EvalError::Overflow(_)));
+        assert_eq!(error.to_string(), "Eval Error: overflow in add in (add 9223372036854775807 1)");
+        let mut elem = Elem::Atom(Atom::Number(1));
+        for _ in 0..100000 {
+            elem = Elem::Call(vec![Elem::Atom(Atom::Symbol("add")), Elem::Atom(Atom::Number(1)), elem]);
+        }
+        let elem = Elem::Call(vec![Elem::Atom(Atom::Symbol("add")), Elem::Atom(Atom::Number(isize::MAX)), elem]);
+        let error = elem.eval(&mut env).unwrap_err();
+        assert!(matches!(error.root_cause(), EvalError::Overflow(_)));
+        assert!(error.to_string().ends_with(" in (add 9223372036854775807 (add 1 (add 1 (add 1 (add 1 (add 1 (add 1 (add 1 (add 1 ...)))))))))"));
+    }
+
+    #[test]
+    fn with_env_test() {
+        let mut env = HashMap::new();
+        run("(let x 5)", &mut env);
+        assert_eq!(run("(with-env [[x 1] [y 2]] (list x y))", &mut env), "[1 2]");
+        assert_eq!(run("(list x y)", &mut env), "[5 y]");
+    }
+
+    #[test]
+    fn eval_error_recovery() {
+        let mut env = HashMap::new();
+        let mut input = "(let x 1) (head) (add x 2)";
+        let mut results = Vec::new();
+        while !input.trim().is_empty() {
+            let (rest, result) = eval_and_print(input, &mut env).unwrap();
+            results.push(result.map(|elem| elem.to_string()));
+            input = rest;
+        }
+        assert_eq!(results.len(), 3);
+        assert!(matches!(results[1].as_ref().map_err(EvalError::root_cause), Err(EvalError::MissingArgument(_))));
+        assert_eq!(results[2].as_ref().unwrap(), "3");
+    }
+
+    #[test]
+    fn eval_all_test() {
+        let mut env = HashMap::new();
+        let results = eval_all("(let x 1) (cons x) (add x 2) (tail", &mut env);
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().to_string(), "x");
+        assert!(matches!(results[1].as_ref().map_err(EvalError::root_cause), Err(EvalError::MissingArgument(_))));
+        assert_eq!(results[2].as_ref().unwrap().to_string(), "3");
+        assert!(matches!(results[3], Err(EvalError::Parse(_))));
+    }
+
+    #[test]
+    fn take_nth_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(take-nth 2 [a b c d e])", &mut env), "[a c e]");
+        assert_eq!(run("(take-nth 1 [a b c])", &mut env), "[a b c]");
+        assert!(matches!(try_run("(take-nth 0 [a b c])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn scan_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(scan add 0 [1 2 3])", &mut env), "[0 1 3 6]");
+        assert_eq!(run("(scan (fun [acc x] (cons x acc)) [] [a b])", &mut env), "[[] [a] [b a]]");
+        assert_eq!(run("(scan add 5 [])", &mut env), "[5]");
+    }
+
+    #[test]
+    fn find_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(find (fun [n] (gt n 2)) [1 2 3 4])", &mut env), "3");
+        assert_eq!(run("(find (fun [n] (gt n 9)) [1 2 3 4])", &mut env), "[]");
+        assert_eq!(run("(find atom [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn remove_test() {
+        let mut env = HashMap::new();
+        run("(let even (fun [n] (if (lt n 2) (eq n 0) (even (add n -2)))))", &mut env);
+        assert_eq!(run("(remove even [1 2 3 4 5])", &mut env), "[1 3 5]");
+        assert_eq!(run("(remove (fun [n] (gt n 9)) [1 2 3])", &mut env), "[1 2 3]");
+    }
+
+    #[test]
+    fn sequence_equality() {
+        let (_, list) = expr("[a [1 2] \"s\"]").unwrap();
+        let (_, call) = expr("(a (1 2) \"s\")").unwrap();
+        assert_eq!(list, expr("[a [1 2] \"s\"]").unwrap().1);
+        assert_eq!(call, expr("(a (1 2) \"s\")").unwrap().1);
+        assert_eq!(list, call);
+        assert_ne!(list, expr("[a [1 3] \"s\"]").unwrap().1);
+        assert_ne!(list, expr("[a [1 2]]").unwrap().1);
+    }
+
+    #[test]
+    fn truth_predicates() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(list (nil? []) (nil? #t) (nil? 0) (nil? [a]))", &mut env), "[#t [] [] []]");
+        assert_eq!(run("(list (true? #t) (true? (eq 1 1)) (true? []) (true? #x))", &mut env), "[#t #t [] []]");
+        assert_eq!(run("(list (false? []) (false? (eq 1 2)) (false? #t) (false? 0))", &mut env), "[#t #t [] []]");
+    }
+
+    #[test]
+    fn inc_dec() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(list (inc 1) (dec 1) (inc -5))", &mut env), "[2 0 -4]");
+        assert_eq!(run("(inc [1 2 3])", &mut env), "[2 3 4]");
+        assert_eq!(run("(dec [1 2 3])", &mut env), "[0 1 2]");
+        assert!(matches!(try_run("(inc [1 a])", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert_eq!(run("(inc (div 1 2))", &mut env), "3/2");
+        #[cfg(not(feature = "bignum"))]
+        assert!(matches!(try_run("(inc 9223372036854775807)", &mut env), Err(EvalError::Overflow(_))));
+        #[cfg(not(feature = "bignum"))]
+        assert!(matches!(try_run("(dec (list 0 (sub -9223372036854775807 1)))", &mut env), Err(EvalError::Overflow(_))));
+        #[cfg(feature = "bignum")]
+        assert_eq!(run("(inc 9223372036854775807)", &mut env), "9223372036854775808");
+        #[cfg(feature = "bignum")]
+        assert_eq!(run("(dec (list 0 (inc 9223372036854775807)))", &mut env), "[-1 9223372036854775807]");
+    }
+
+    #[test]
+    fn repeat_str_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(repeat-str 3 \"ab\")", &mut env), "\"ababab\"");
+        assert_eq!(run("(repeat-str 0 \"ab\")", &mut env), "\"\"");
+        assert_eq!(run("(repeat-str -2 \"ab\")", &mut env), "\"\"");
+        assert!(matches!(try_run("(repeat-str 9223372036854775807 \"ab\")", &mut env), Err(EvalError::LimitExceeded(_))));
+        assert!(matches!(try_run("(repeat-str 600000 \"ab\")", &mut env), Err(EvalError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn trim_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(trim \"  hi there \")", &mut env), "\"hi there\"");
+        assert_eq!(run("(trim-start \"  hi \")", &mut env), "\"hi \"");
+        assert_eq!(run("(trim-end \"  hi \")", &mut env), "\"  hi\"");
+        assert_eq!(run("(trim \"hi\")", &mut env), "\"hi\"");
+        assert!(matches!(try_run("(trim 5)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn affix_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(list (starts-with? \"hello\" \"he\") (starts-with? \"hello\" \"lo\"))", &mut env), "[#t []]");
+        assert_eq!(run("(list (ends-with? \"hello\" \"lo\") (ends-with? \"hello\" \"he\"))", &mut env), "[#t []]");
+        assert!(matches!(try_run("(starts-with? hello \"he\")", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn lines_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(lines \"a\nb\nc\")", &mut env), "[\"a\" \"b\" \"c\"]");
+        assert_eq!(run("(lines \"a\r\nb\r\n\")", &mut env), "[\"a\" \"b\"]");
+        assert_eq!(run("(lines \"a\n\nb\n\")", &mut env), "[\"a\" \"\" \"b\"]");
+    }
+
+    #[test]
+    fn words_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(words \"  hello   world \")", &mut env), "[\"hello\" \"world\"]");
+        assert_eq!(run("(words \"one\ttwo\nthree\")", &mut env), "[\"one\" \"two\" \"three\"]");
+        assert_eq!(run("(words \"\")", &mut env), "[]");
+    }
+
+    #[test]
+    fn zero_pad_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(zero-pad 3 7)", &mut env), "\"007\"");
+        assert_eq!(run("(zero-pad 2 1234)", &mut env), "\"1234\"");
+        assert_eq!(run("(zero-pad 4 -7)", &mut env), "\"-007\"");
+        assert_eq!(run("(str-len (zero-pad 70000 1))", &mut env), "70000");
+        assert!(matches!(try_run("(zero-pad 9223372036854775807 1)", &mut env), Err(EvalError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn clamp_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(list (clamp 0 10 -5) (clamp 0 10 5) (clamp 0 10 15))", &mut env), "[0 5 10]");
+        assert!(matches!(try_run("(clamp 10 0 5)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn gcd_lcm() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(list (gcd 8 15) (lcm 8 15))", &mut env), "[1 120]");
+        assert_eq!(run("(list (gcd 12 18 -24) (lcm 4 6 10))", &mut env), "[6 60]");
+        assert_eq!(run("(list (gcd 0 9) (gcd 0 0) (lcm 0 9))", &mut env), "[9 0 0]");
+        assert!(matches!(try_run("(lcm 9223372036854775807 9223372036854775806)", &mut env), Err(EvalError::Overflow(_))));
+        assert!(matches!(try_run("(gcd 4)", &mut env), Err(EvalError::MissingArgument(_))));
+    }
+
+    #[test]
+    fn seeded_rand() {
+        let mut env = HashMap::new();
+        seed_rng(42);
+        let first = run("(list (rand 100) (rand 100) (rand 100) (rand))", &mut env);
+        seed_rng(42);
+        assert_eq!(run("(list (rand 100) (rand 100) (rand 100) (rand))", &mut env), first);
+        for _ in 0..100 {
+            let value: isize = run("(rand 6)", &mut env).parse().unwrap();
+            assert!((0..6).contains(&value));
+        }
+        assert!(matches!(try_run("(rand 0)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn seeded_shuffle() {
+        let mut env = HashMap::new();
+        seed_rng(7);
+        let shuffled = run("(shuffle [1 2 3 4 5 6])", &mut env);
+        assert_eq!(shuffled, "[2 6 1 3 5 4]");
+        let mut numbers: Vec<&str> = shuffled.trim_matches(['[', ']']).split(' ').collect();
+        numbers.sort();
+        assert_eq!(numbers, ["1", "2", "3", "4", "5", "6"]);
+        assert_eq!(run("(shuffle [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn seeded_sampling() {
+        let mut env = HashMap::new();
+        seed_rng(7);
+        assert_eq!(run("(list (rand-nth [a b c d]) (rand-nth [a b c d]))", &mut env), "[d a]");
+        assert_eq!(run("(sample 3 [1 2 3 4 5 6])", &mut env), "[1 5 2]");
+        assert_eq!(run("(sample 9 [1 2])", &mut env).len(), 5);
+        assert_eq!(run("(rand-nth [])", &mut env), "[]");
+        assert_eq!(run("(sample 2 [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn lazy_range() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(take 5 (naturals))", &mut env), "[0 1 2 3 4]");
+        assert_eq!(run("(take 3 (lazy-range 5 1000000000000000))", &mut env), "[5 6 7]");
+        assert_eq!(run("(take 9 (lazy-range 3))", &mut env), "[0 1 2]");
+        assert_eq!(run("(head (tail (naturals)))", &mut env), "1");
+        assert_eq!(run("(take 2 [a b c])", &mut env), "[a b]");
+    }
+
+    #[test]
+    fn lazy_map_filter() {
+        let mut env = HashMap::new();
+        run("(let even (fun [n] (if (lt n 2) (eq n 0) (even (add n -2)))))", &mut env);
+        assert_eq!(run("(take 3 (map inc (naturals)))", &mut env), "[1 2 3]");
+        assert_eq!(run("(take 4 (filter even (map inc (naturals))))", &mut env), "[2 4 6 8]");
+        assert_eq!(run("(take 9 (filter even (lazy-range 5)))", &mut env), "[0 2 4]");
+        assert_eq!(run("(map inc [1 2])", &mut env), "[2 3]");
+        assert_eq!(run("(filter even [1 2 3 4])", &mut env), "[2 4]");
+    }
+
+    #[test]
+    fn realize_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(realize (map inc (lazy-range 3)))", &mut env), "[1 2 3]");
+        assert_eq!(run("(realize [a b])", &mut env), "[a b]");
+        assert!(matches!(try_run("(realize (naturals))", &mut env), Err(EvalError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn delay_force() {
+        let mut env = HashMap::new();
+        seed_rng(3);
+        let expected = run("(list (rand) (rand))", &mut env);
+        seed_rng(3);
+        let forced = run("((fun [d] (list (force d) (force d) (rand))) (delay (rand)))", &mut env);
+        let (first, second) = expected.trim_matches(['[', ']']).split_once(' ').unwrap();
+        assert_eq!(forced, format!("[{} {} {}]", first, first, second));
+        assert_eq!(run("(force 5)", &mut env), "5");
+    }
+
+    #[test]
+    fn cond_thread() {
+        let mut env = HashMap::new();
+        run("(let x 5)", &mut env);
+        assert_eq!(run("(cond-> x [(gt x 3) (add 10)] [(lt x 3) inc] [#t (cons [a])])", &mut env), "[15 a]");
+        assert_eq!(run("(cond-> x [(lt x 3) inc])", &mut env), "5");
+        assert!(matches!(try_run("(cond-> 1 [])", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(cond-> 1 [#t inc dec])", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(cond-> 1 x)", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(arg(&[], 0), Err(EvalError::MissingArgument(_))));
+    }
+
+    #[test]
+    fn case_insensitive_symbols() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(Add 1 2)", &mut env), "(Add 1 2)");
+        set_config(EvalConfig { case_insensitive: true });
+        assert_eq!(run("(Add 1 2)", &mut env), "3");
+        assert_eq!(run("(let Double (fun [N] (ADD n n))) (DOUBLE 4)", &mut env), "8");
+        assert_eq!(run("(with-env [[X 1]] X)", &mut env), "1");
+        assert_eq!(run("(when-let [V 1] V)", &mut env), "1");
+        assert_eq!(run("(if-let [V 1] V no)", &mut env), "1");
+        set_config(EvalConfig::default());
+        assert_eq!(run("(Add 1 2)", &mut env), "(Add 1 2)");
+    }
+
+    #[test]
+    fn format_source_test() {
+        assert_eq!(format_source("  (let   x 7)(let double(fun [n]\n   (add n n)))\n").unwrap(), "(let x 7)\n(let double (fun [n] (add n n)))\n");
+        let ugly = "(let reverse (fun [l] (if (not l) () (append (reverse (tail l)) (head l)))))";
+        let pretty = "\
+(let
+  reverse
+  (fun
+    [l]
+    (if (not l) () (append (reverse (tail l)) (head l)))))
+";
+        assert_eq!(format_source(ugly).unwrap(), pretty);
+        assert!(matches!(format_source("(let x"), Err(EvalError::Parse(_))));
+    }
+
+    #[test]
+    fn pipe_symbols() {
+        let mut env = HashMap::new();
+        assert!(matches!(expr("|weird symbol|").unwrap().1, Elem::Atom(Atom::Symbol("weird symbol"))));
+        assert!(matches!(expr("|f(x)|").unwrap().1, Elem::Atom(Atom::Symbol("f(x)"))));
+        assert_eq!(run("(let |my value| 3) (add |my value| 1)", &mut env), "4");
+        assert_eq!(run("[|a b| |(c)| d]", &mut env), "[|a b| |(c)| d]");
+    }
+
+    #[test]
+    fn char_literals() {
+        let mut env = HashMap::new();
+        assert!(matches!(expr("\\a").unwrap().1, Elem::Atom(Atom::Char('a'))));
+        assert!(matches!(expr("\\space").unwrap().1, Elem::Atom(Atom::Char(' '))));
+        assert!(matches!(expr("\\(").unwrap().1, Elem::Atom(Atom::Char('('))));
+        assert_eq!(run("[\\a \\space \\newline \\( \\Z]", &mut env), "[\\a \\space \\newline \\( \\Z]");
+        assert_eq!(run("(list (eq \\a \\a) (lt \\a \\b) (gt \\a \\b) (eq \\a \"a\"))", &mut env), "[#t #t [] []]");
+        assert!(matches!(expr("\\abc").unwrap().1, Elem::Atom(Atom::Symbol("\\abc"))));
+    }
+
+    #[test]
+    fn string_to_list() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(to-list \"abc\")", &mut env), "[\\a \\b \\c]");
+        assert_eq!(run("(join (to-list \"a c\"))", &mut env), "\"a c\"");
+        assert_eq!(run("(join (list \"ab\" \\c))", &mut env), "\"abc\"");
+        assert_eq!(run("(to-list \"\")", &mut env), "[]");
+    }
+
+    #[test]
+    fn ord_chr() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(list (ord \"A\") (ord \\a) (chr 65))", &mut env), "[65 97 \"A\"]");
+        assert_eq!(run("(list (chr -1) (chr 55296) (chr 1114112))", &mut env), "[[] [] []]");
+        assert!(matches!(try_run("(ord \"AB\")", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn count_matches() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(count-matches \"ab\" \"abcabab\")", &mut env), "3");
+        assert_eq!(run("(count-matches \"aa\" \"aaaa\")", &mut env), "2");
+        assert_eq!(run("(count-matches \"x\" \"abc\")", &mut env), "0");
+        assert_eq!(run("(count-matches \"\" \"abc\")", &mut env), "0");
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_match() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(regex-match \"(\\w+)@(\\w+)\\.com\" \"mail bob@example.com now\")", &mut env), "[\"bob@example.com\" \"bob\" \"example\"]");
+        assert_eq!(run("(regex-match \"^a(b)?c{2,3}$\" \"accc\")", &mut env), "[\"accc\" []]");
+        assert_eq!(run("(regex-match \"[0-9]+|x\" \"ab x 42\")", &mut env), "[\"x\"]");
+        assert_eq!(run("(regex-match \"\\d+\" \"none here\")", &mut env), "[]");
+        assert!(matches!(try_run("(regex-match \"(ab\" \"ab\")", &mut env), Err(EvalError::InvalidPattern(_))));
+        assert!(matches!(try_run("(regex-match \"*a\" \"a\")", &mut env), Err(EvalError::InvalidPattern(_))));
+        assert!(matches!(try_run("(regex-match \"a{1000}{1000}\" \"a\")", &mut env), Err(EvalError::InvalidPattern(_))));
+        assert_eq!(run("(regex-match \"a|ab\" \"ab\")", &mut env), "[\"a\"]");
+        assert_eq!(run("(regex-match \"(a|ab)(c|bcd)\" \"abcd\")", &mut env), "[\"abcd\" \"a\" \"bcd\"]");
+        assert_eq!(run("(regex-match \"(a*)*$\" \"aa\")", &mut env), "[\"aa\" \"aa\"]");
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_match_long_input() {
+        let source = format!("(str-len (head (regex-match \"a*\" \"{}\")))", "a".repeat(200_000));
+        let pathological = format!("(regex-match \"(a*)*b\" \"{}c\")", "a".repeat(1000));
+        let mut env = HashMap::new();
+        assert_eq!(run(&source, &mut env), "200000");
+        assert_eq!(run(&pathological, &mut env), "[]");
+    }
+
+    #[test]
+    fn save_load_env() {
+        let path = std::env::temp_dir().join(format!("qbscript-env-{}.qb", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut env = HashMap::new();
+        run("(let x 7) (let greeting \"hi there\") (let double (fun [n] (add n n))) (let |odd name| [a #b \\c])", &mut env);
+        env.insert("lazy", Elem::Lazy(Seq::Range(0, None)));
+        save_env(&env, path).unwrap();
+        let mut fresh = HashMap::new();
+        load_env(path, &mut fresh).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(run("(list x greeting (double 4) |odd name|)", &mut fresh), "[7 \"hi there\" 8 [a #b \\c]]");
+        assert!(!fresh.contains_key("lazy"));
+    }
+
+    #[test]
+    fn file_builtins() {
+        let path = std::env::temp_dir().join(format!("qbscript-builtins-{}.qb", std::process::id()));
+        let source = format!("(let x 7) (save-env \"{}\")", path.display());
+        let reload = format!("(load-env \"{}\") x", path.display());
+        let mut env = HashMap::new();
+        assert_eq!(run(&source, &mut env), format!("(save-env \"{}\")", path.display()));
+        assert!(!path.exists());
+        register_file_builtins();
+        assert_eq!(run(&source, &mut env), "#t");
+        let mut fresh = HashMap::new();
+        assert_eq!(run(&reload, &mut fresh), "7");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn documented_functions() {
+        let mut env = HashMap::new();
+        run("(defn double [n] \"returns n doubled\" (add n n))", &mut env);
+        run("(defn greet [] \"hello\")", &mut env);
+        assert_eq!(run("(double 21)", &mut env), "42");
+        assert_eq!(run("(doc double)", &mut env), "\"returns n doubled\"");
+        assert_eq!(run("(greet)", &mut env), "\"hello\"");
+        assert_eq!(run("(doc greet)", &mut env), "[]");
+        assert_eq!(run("(doc (fun [x] \"ident\" x))", &mut env), "\"ident\"");
+    }
+
+    #[test]
+    fn builtin_listing() {
+        let mut env = HashMap::new();
+        let names = run("(builtins)", &mut env);
+        for name in ["add", "cons", "head", "let", "map", "builtins"] {
+            assert!(names.trim_matches(['[', ']']).split(' ').any(|listed| listed == name), "{} not listed", name);
+        }
+        assert_eq!(run("(apropos \"trim\")", &mut env), "[trim trim-end trim-start]");
+        assert_eq!(run("(apropos \"zzz\")", &mut env), "[]");
+    }
+
+    #[test]
+    fn builtin_registry() {
+        for name in ["add", "inc", "dec", "gcd", "list", "cons", "head", "tail", "map", "filter"] {
+            assert!(builtin(name).is_some(), "{} not registered", name);
+        }
+        assert!(builtin("no-such-builtin").is_none());
+        let mut env = HashMap::new();
+        assert_eq!(run("(list (add 1 2) (head [a b]) (map inc [1 2]) (cond [[] x] [#t y]))", &mut env), "[3 a [2 3] y]");
+    }
+
+    #[test]
+    fn custom_builtin() {
+        fn triple<'a>(items: &[Elem<'a>], env: &mut HashMap<&'a str,Elem<'a>>) -> EvalResult<'a> {
+            match eval_arg(items,1,env)?.as_number() {
+                Some(value) => Ok(Elem::Atom(Atom::Number(value * 3))),
+                None => Err(EvalError::InvalidArgument("triple".to_string()))
+            }
+        }
+        register_builtin("triple", triple);
+        let mut env = HashMap::new();
+        assert_eq!(run("(triple (add 1 1))", &mut env), "6");
+        assert_eq!(run("(map triple [1 2])", &mut env), "[3 6]");
+        assert!(run("(builtins)", &mut env).contains("triple"));
+        register_builtin("add", |_, _| Ok(Elem::Atom(Atom::Symbol("replaced"))));
+        assert_eq!(run("(list (add 1 2) (list (add 3)))", &mut env), "[replaced [replaced]]");
+    }
+
+    #[test]
+    fn spy_test() {
+        let mut env = HashMap::new();
+        capture_output(true);
+        assert_eq!(run("(inc (spy \"before\" (add 1 2)))", &mut env), "4");
+        assert_eq!(run("(spy step [a b])", &mut env), "[a b]");
+        assert_eq!(take_output(), "before: 3\nstep: [a b]\n");
+        eval_and_print("(spy x 1)", &mut env).unwrap().1.unwrap();
+        assert_eq!(take_output(), "x: 1\n1\n");
+        capture_output(false);
+    }
+
+    #[test]
+    fn error_form_test() {
+        let mut env = HashMap::new();
+        let error = (expr("(list 1 (inc (head [a])) 3)").unwrap().1).eval(&mut env).unwrap_err();
+        assert!(matches!(error.root_cause(), EvalError::InvalidArgument(_)));
+        assert_eq!(error.to_string(), "Eval Error: invalid argument to inc in (inc (head [a]))");
+        let error = (expr("((fun [n] (add n 1)) (gcd 4))").unwrap().1).eval(&mut env).unwrap_err();
+        assert_eq!(error.to_string(), "Eval Error: missing argument to gcd in (gcd 4)");
+    }
+
+    #[test]
+    fn with_timeout_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(with-timeout 1000 (add 1 2))", &mut env), "3");
+        assert!(matches!(try_run("(with-timeout 10 (head (filter nil? (naturals))))", &mut env), Err(EvalError::Timeout(_))));
+        assert_eq!(run("(with-timeout 50 (head (take 3 (naturals))))", &mut env), "0");
+        set_timeout(Some(Duration::from_millis(10)));
+        let error = (expr("(head (filter nil? (naturals)))").unwrap().1).eval(&mut env).unwrap_err();
+        set_timeout(None);
+        assert!(matches!(error.root_cause(), EvalError::Timeout(_)));
+    }
+
+    #[test]
+    fn quote_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("'a", &mut env), "#a");
+        assert_eq!(run("'(a (b c))", &mut env), "[a [b c]]");
+        assert_eq!(run("(eq 'a 'a)", &mut env), "#t");
+        assert_eq!(run("(eq 'a #a)", &mut env), "#t");
+        assert_eq!(run("(eq 'a 'b)", &mut env), "[]");
+        assert_eq!(run("(equal '(a b) '(a b))", &mut env), "#t");
+        assert_eq!(run("(equal '(a (b)) [a [b]])", &mut env), "#t");
+        assert_eq!(run("(equal '(a b) '(b a))", &mut env), "[]");
+        assert_eq!(run("(let x 1) (head '(x))", &mut env), "x");
+    }
+
+    #[test]
+    fn mul_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(mul 6 7)", &mut env), "42");
+        assert_eq!(run("(mul)", &mut env), "1");
+        assert!(matches!(try_run("(mul 2 a)", &mut env), Err(EvalError::InvalidArgument(_))));
+        let product = try_run("(mul 1000000000000 1000000000000)", &mut env);
+        #[cfg(feature = "bignum")]
+        assert_eq!(product.unwrap(), "1000000000000000000000000");
+        #[cfg(not(feature = "bignum"))]
+        assert!(matches!(product, Err(EvalError::Overflow(_))));
+    }
+
+    #[cfg(feature = "bignum")]
+    #[test]
+    fn bignum_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(mul -1000000000000 1000000000000 1000000000000)", &mut env), "-1000000000000000000000000000000000000");
+        assert_eq!(run("(add 9223372036854775807 1)", &mut env), "9223372036854775808");
+        assert_eq!(run("(add (add 9223372036854775807 1) -1)", &mut env), "9223372036854775807");
+        assert_eq!(run("(mul (mul 4294967296 4294967296) 0)", &mut env), "0");
+        assert_eq!(BigInt::from(isize::MIN).to_string(), isize::MIN.to_string());
+        assert!(BigInt::from(-5) < BigInt::from(3));
+    }
+
+    #[cfg(feature = "bignum")]
+    #[test]
+    fn bignum_compare_test() {
+        let mut env = HashMap::new();
+        run("(defn big [] (mul 10000000000 10000000000))", &mut env);
+        assert_eq!(run("(list (gt (big) 1) (lt (big) 1) (le (big) 1) (ge (big) 1))", &mut env), "[#t [] [] #t]");
+        assert_eq!(run("(list (lt (sub 0 (big)) -1) (gt (big) (div 1 2)) (lt (big) (add (big) 1)))", &mut env), "[#t #t #t]");
+    }
+
+    #[cfg(feature = "bignum")]
+    #[test]
+    fn bignum_extreme_test() {
+        let mut env = HashMap::new();
+        run("(defn big [] (mul 10000000000 10000000000))", &mut env);
+        assert_eq!(run("(max 1 (big) (div 1 2))", &mut env), "100000000000000000000");
+        assert_eq!(run("(min 1 (sub 0 (big)) (div 1 2))", &mut env), "-100000000000000000000");
+        assert_eq!(run("(median (list 3 (big) 1))", &mut env), "3");
+        assert_eq!(run("(mean (list (big) (big)))", &mut env), "100000000000000000000");
+    }
+
+    #[cfg(feature = "bignum")]
+    #[test]
+    fn bignum_div_test() {
+        let mut env = HashMap::new();
+        run("(defn big [] (mul 10000000000 10000000000))", &mut env);
+        assert_eq!(run("(div (big) 10000000000)", &mut env), "10000000000");
+        assert_eq!(run("(div (big) (big))", &mut env), "1");
+        assert_eq!(run("(mul (big) (div 1 4))", &mut env), "25000000000000000000");
+        assert!(matches!(try_run("(add (big) (div 1 2))", &mut env), Err(EvalError::Overflow(_))));
+        assert_eq!(run("(div (mul 6 (big)) (mul 4 (big)))", &mut env), "3/2");
+        assert!(matches!(try_run("(div 7 (big))", &mut env), Err(EvalError::Overflow(_))));
+        assert_eq!(BigInt::from(-7).divmod(&BigInt::from(2)), Some((BigInt::from(-3), BigInt::from(-1))));
+        assert!(matches!(try_run("(div (big) 0)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn overflow_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(add 9223372036854775806 1)", &mut env), "9223372036854775807");
+        assert_eq!(run("(sub 10 3 2)", &mut env), "5");
+        assert_eq!(run("(sub 4)", &mut env), "-4");
+        assert!(matches!(try_run("(sub a 1)", &mut env), Err(EvalError::InvalidArgument(_))));
+        let sum = try_run("(add 9223372036854775807 9223372036854775807)", &mut env);
+        let difference = try_run("(sub -9223372036854775807 2)", &mut env);
+        let negated = try_run("(sub (sub -9223372036854775807 1))", &mut env);
+        #[cfg(feature = "bignum")]
+        {
+            assert_eq!(sum.unwrap(), "18446744073709551614");
+            assert_eq!(difference.unwrap(), "-9223372036854775809");
+            assert_eq!(negated.unwrap(), "9223372036854775808");
+        }
+        #[cfg(not(feature = "bignum"))]
+        {
+            assert!(matches!(sum, Err(EvalError::Overflow(_))));
+            assert!(matches!(difference, Err(EvalError::Overflow(_))));
+            assert!(matches!(negated, Err(EvalError::Overflow(_))));
+        }
     }
-}
 
-pub fn eval_and_print<'a>(input:&'a str,env:&mut HashMap<&'a str,Elem<'a>>) -> Result<&'a str,Box<dyn Error + 'a>>{
-    let (input, elem) = expr(input)?;
-    println!("{}",elem.eval(env));
-    Ok(input)
-}
+    #[test]
+    fn ratio_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(div 1 3)", &mut env), "1/3");
+        assert_eq!(run("(div 6 -4)", &mut env), "-3/2");
+        assert_eq!(run("(div 100 5 2)", &mut env), "10");
+        assert_eq!(run("(add (div 1 3) (div 1 6))", &mut env), "1/2");
+        assert_eq!(run("(add (div 1 3) (div 2 3))", &mut env), "1");
+        assert_eq!(run("(sub (div 1 2) 1)", &mut env), "-1/2");
+        assert_eq!(run("(mul (div 2 3) (div 9 4))", &mut env), "3/2");
+        assert_eq!(run("(div (div 1 2) (div 1 4))", &mut env), "2");
+        assert_eq!(run("(lt (div 1 3) (div 1 2))", &mut env), "#t");
+        assert_eq!(run("(gt (div 7 2) 3)", &mut env), "#t");
+        assert!(matches!(try_run("(div 1 0)", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(add (div 1 9223372036854775807) (div 1 9223372036854775806))", &mut env), Err(EvalError::Overflow(_))));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn deftype_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(deftype point [x y])", &mut env), "point");
+        assert_eq!(run("(point 3 (add 2 2))", &mut env), "[point 3 4]");
+        assert_eq!(run("(point-x (point 3 4))", &mut env), "3");
+        assert_eq!(run("(point-y (point 3 4))", &mut env), "4");
+        assert_eq!(run("((fun [p] (add (point-x p) (point-y p))) (point 5 6))", &mut env), "11");
+        assert_eq!(run("(point-z p)", &mut env), "(point-z p)");
+        assert!(matches!(try_run("(point-x [1 2])", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(point 1)", &mut env), Err(EvalError::MissingArgument(_))));
+    }
 
     #[test]
-    fn parsing() {
-        let result = expr("(cons #A [B C :D \"EFG\" 1 2 3])");
-        println!("{}",result.unwrap().1.eval(&mut HashMap::new()));
+    fn division_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(div 100 5 2)", &mut env), "10");
+        assert_eq!(run("(quot 100 5 2)", &mut env), "10");
+        assert_eq!(run("(quot 7 2)", &mut env), "3");
+        assert_eq!(run("(quot -7 2)", &mut env), "-3");
+        assert!(matches!(try_run("(div 10 0)", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(quot 10 0)", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert_eq!(run("(quot -9223372036854775807 -1 -1)", &mut env), "-9223372036854775807");
+        assert!(matches!(try_run("(quot (sub -9223372036854775807 1) -1)", &mut env), Err(EvalError::Overflow(_))));
     }
 
     #[test]
-    fn things() {
-        let result = expr("(value (head [:KEY #VALUE]))");
-        println!("{}",result.unwrap().1.eval(&mut HashMap::new()));
+    fn match_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(match (list 1 2) [[a b] (add a b)] [n n])", &mut env), "3");
+        assert_eq!(run("(match 5 [[a b] (add a b)] [n n])", &mut env), "5");
+        assert_eq!(run("(match [1 2 3] [[a b] a] [0 zero])", &mut env), "[]");
+        assert_eq!(run("(match 0 [1 one] [0 zero] [_ other])", &mut env), "zero");
+        assert_eq!(run("(match [1 [2 3]] [[_ [x y]] (list y x)])", &mut env), "[3 2]");
+        assert_eq!(run("(match 4 [n n]) n", &mut env), "n");
+        assert!(matches!(try_run("(match 1 n)", &mut env), Err(EvalError::InvalidArgument(_))));
     }
 
     #[test]
-    fn numbers() {
-        let result = expr("(let second (car (cdr x)))");
+    fn when_let_test() {
         let mut env = HashMap::new();
-        println!("{}",result.unwrap().1.eval(&mut env));
-        let result2 = expr("(second A B C)");
-        println!("{}",result2.unwrap().1.eval(&mut env));
+        assert_eq!(run("(when-let [v (head [a b])] (list v v))", &mut env), "[a a]");
+        assert_eq!(run("(when-let [v (add 1 2)] (add v v))", &mut env), "6");
+        assert_eq!(run("(when-let [v (tail [1])] (head v))", &mut env), "[]");
+        assert_eq!(run("(when-let [v 1] v) v", &mut env), "v");
+        assert!(matches!(try_run("(when-let v 1)", &mut env), Err(EvalError::InvalidArgument(_))));
     }
 
     #[test]
-    fn cond_test() {
-        let result = expr("(cond [(le (add 3 2) 5) \"3 + 2 <= 5\"] [T \"Catch-all\"])");
-        println!("{}",result.unwrap().1.eval(&mut HashMap::new()));
+    fn mod_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(mod 17 5)", &mut env), "2");
+        assert_eq!(run("(mod -7 3)", &mut env), "2");
+        assert_eq!(run("(mod 7 -3)", &mut env), "1");
+        assert!(matches!(try_run("(mod 7 0)", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(mod 7)", &mut env), Err(EvalError::MissingArgument(_))));
     }
 
     #[test]
-    fn fun_test() {
-        let result = expr("(let tri (fun [n] (if (gt n 0) (add n (tri (add n -1))) (0))))");
+    fn if_let_test() {
         let mut env = HashMap::new();
-        println!("{}",result.unwrap().1.eval(&mut env));
-        let result2 = expr("(tri 5)");
-        println!("{}",result2.unwrap().1.eval(&mut env));
+        assert_eq!(run("(if-let [v (add 1 2)] (list v v) (list v))", &mut env), "[3 3]");
+        assert_eq!(run("(if-let [v (tail [1])] (list v v) (list v))", &mut env), "[v]");
+        assert_eq!(run("(if-let [v 1] v v) v", &mut env), "v");
+        assert!(matches!(try_run("(if-let v 1 2)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn min_max_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(min 3 -1 2)", &mut env), "-1");
+        assert_eq!(run("(max 3 -1 (add 2 5))", &mut env), "7");
+        assert_eq!(run("(max (div 1 2) (div 1 3))", &mut env), "1/2");
+        assert_eq!(run("(min 4)", &mut env), "4");
+        assert_eq!(run("(max)", &mut env), "[]");
+        assert!(matches!(try_run("(min 1 a)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn prog_test() {
+        let mut env = HashMap::new();
+        capture_output(true);
+        assert_eq!(run("(prog1 (add 1 0) (spy \"side\" 2))", &mut env), "1");
+        assert_eq!(run("(progn (spy \"first\" 1) (add 1 1))", &mut env), "2");
+        assert_eq!(take_output(), "side: 2\nfirst: 1\n");
+        capture_output(false);
+        assert_eq!(run("(progn)", &mut env), "[]");
+        assert!(matches!(try_run("(prog1)", &mut env), Err(EvalError::MissingArgument(_))));
+    }
+
+    #[test]
+    fn abs_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(abs -5)", &mut env), "5");
+        assert_eq!(run("(abs 5)", &mut env), "5");
+        assert_eq!(run("(abs (div -1 2))", &mut env), "1/2");
+        assert_eq!(run("(abs a)", &mut env), "a");
+        assert_eq!(run("(div (sub -9223372036854775807 1) 3)", &mut env), "-9223372036854775808/3");
+        assert!(matches!(try_run("(abs (div (sub -9223372036854775807 1) 3))", &mut env), Err(EvalError::Overflow(_))));
+        #[cfg(not(feature = "bignum"))]
+        assert!(matches!(try_run("(abs (sub -9223372036854775807 1))", &mut env), Err(EvalError::Overflow(_))));
+        #[cfg(feature = "bignum")]
+        assert_eq!(run("(abs (sub -9223372036854775807 1))", &mut env), "9223372036854775808");
+        #[cfg(feature = "bignum")]
+        assert_eq!(run("(abs (sub 0 (mul 10000000000 10000000000)))", &mut env), "100000000000000000000");
+    }
+
+    #[test]
+    fn for_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(for [i (lazy-range 5)] (mul i i))", &mut env), "[0 1 4 9 16]");
+        assert_eq!(run("(for [x [a b]] (list x x))", &mut env), "[[a a] [b b]]");
+        assert_eq!(run("(for [i (lazy-range 10) :when (eq (mod i 3) 0)] (inc i))", &mut env), "[1 4 7 10]");
+        assert_eq!(run("(for [i []] i)", &mut env), "[]");
+        assert!(matches!(try_run("(for [i (lazy-range 3) :unless i] i)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn and_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(and (gt 3 2) (lt 1 5))", &mut env), "#t");
+        assert_eq!(run("(and 1 2 3)", &mut env), "3");
+        assert_eq!(run("(and (gt 3 2) [] 3)", &mut env), "[]");
+        assert_eq!(run("(and [] (head))", &mut env), "[]");
+        assert_eq!(run("(and)", &mut env), "#t");
+    }
+
+    #[test]
+    fn pred_combinator_test() {
+        let mut env = HashMap::new();
+        run("(defn big? [n] (gt n 2)) (defn small? [n] (lt n 6))", &mut env);
+        assert_eq!(run("(filter (every-pred big? small?) [1 2 3 4 5 6 7])", &mut env), "[3 4 5]");
+        assert_eq!(run("(filter (some-pred small? (fun [n] (gt n 6))) [1 5 6 7])", &mut env), "[1 5 7]");
+        assert_eq!(run("((every-pred big? small?) 9)", &mut env), "[]");
+        assert_eq!(run("((some-pred nil? big?) [])", &mut env), "#t");
+        assert_eq!(run("(let n 0) ((every-pred (fun [x] (eq n x))) 3)", &mut env), "[]");
+        assert_eq!(run("((head (list inc)) 1)", &mut env), "2");
+    }
+
+    #[test]
+    fn or_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(or [] (eq 1 1))", &mut env), "#t");
+        assert_eq!(run("(or [] 2 (head))", &mut env), "2");
+        assert_eq!(run("(or [] (tail [1]))", &mut env), "[]");
+        assert_eq!(run("(or)", &mut env), "[]");
+    }
+
+    #[test]
+    fn juxt_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("((juxt inc dec) 5)", &mut env), "[6 4]");
+        assert_eq!(run("(map (juxt head tail) [[1 2] [3 4]])", &mut env), "[[1 [2]] [3 [4]]]");
+        assert_eq!(run("((juxt) 5)", &mut env), "[]");
+    }
+
+    #[test]
+    fn equal_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(equal [1 2 3] (list 1 2 3))", &mut env), "#t");
+        assert_eq!(run("(eq [1 2 3] (list 1 2 3))", &mut env), "[]");
+        assert_eq!(run("(equal [1 [2 \"x\"]] (list 1 (list 2 \"x\")))", &mut env), "#t");
+        assert_eq!(run("(equal [1 2] [1 2 3])", &mut env), "[]");
+        assert_eq!(run("(equal a a)", &mut env), "#t");
+        assert_eq!(run("(equal [] (tail [1]))", &mut env), "#t");
+    }
+
+    #[test]
+    fn tap_test() {
+        let mut env = HashMap::new();
+        capture_output(true);
+        assert_eq!(run("(inc (tap (fun [v] (spy \"saw\" (list v))) 5))", &mut env), "6");
+        assert_eq!(run("(tap (fun [v] (spy \"head\" (head v))) [a b])", &mut env), "[a b]");
+        assert_eq!(take_output(), "saw: [5]\nhead: a\n");
+        capture_output(false);
+    }
+
+    #[test]
+    fn compare_strings_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(lt \"apple\" \"banana\")", &mut env), "#t");
+        assert_eq!(run("(gt \"apple\" \"banana\")", &mut env), "[]");
+        assert_eq!(run("(le \"pear\" \"pear\")", &mut env), "#t");
+        assert_eq!(run("(lt apple banana)", &mut env), "#t");
+        assert_eq!(run("(ge zebra apple)", &mut env), "#t");
+        assert_eq!(run("(lt \"1\" 2)", &mut env), "[]");
+        assert_eq!(run("(lt apple \"banana\")", &mut env), "[]");
+    }
+
+    #[test]
+    fn range_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(range 5)", &mut env), "[0 1 2 3 4]");
+        assert_eq!(run("(range 2 5)", &mut env), "[2 3 4]");
+        assert_eq!(run("(range 1 10 3)", &mut env), "[1 4 7]");
+        assert_eq!(run("(range 10 0 -2)", &mut env), "[10 8 6 4 2]");
+        assert_eq!(run("(range 5 0)", &mut env), "[]");
+        assert_eq!(run("(range 9223372036854775806 9223372036854775807 5)", &mut env), "[9223372036854775806]");
+        assert!(matches!(try_run("(range 0 10 0)", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(range 0 10000000)", &mut env), Err(EvalError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn len_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(len [A B C])", &mut env), "3");
+        assert_eq!(run("(len (list))", &mut env), "0");
+        assert_eq!(run("(len a)", &mut env), "0");
+    }
+
+    #[test]
+    fn mode_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(frequencies [a b a [c] [c] a])", &mut env), "[[a 3] [b 1] [[c] 2]]");
+        assert_eq!(run("(mode [1 2 2 3 2 1])", &mut env), "2");
+        assert_eq!(run("(mode [b a a b])", &mut env), "b");
+        assert_eq!(run("(mode [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn nth_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(nth 1 [A B C])", &mut env), "B");
+        assert_eq!(run("(nth 0 (list 1 2))", &mut env), "1");
+        assert_eq!(run("(nth 3 [A B C])", &mut env), "[]");
+        assert_eq!(run("(nth -1 [A B C])", &mut env), "[]");
+        assert!(matches!(try_run("(nth a [A B C])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn map_test() {
+        let mut env = HashMap::new();
+        run("(let double (fun [n] (add n n)))", &mut env);
+        assert_eq!(run("(map double [1 2 3])", &mut env), "[2 4 6]");
+        assert_eq!(run("(map (fun [n] (list n n)) [a b])", &mut env), "[[a a] [b b]]");
+        assert_eq!(run("(map double [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn mean_median_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(mean [1 2 3 4 5])", &mut env), "3");
+        assert_eq!(run("(mean [1 2 3 4])", &mut env), "5/2");
+        assert_eq!(run("(median [5 1 3])", &mut env), "3");
+        assert_eq!(run("(median [4 1 3 2])", &mut env), "5/2");
+        assert_eq!(run("(median (list (div 1 2) (div 1 3) 7))", &mut env), "1/2");
+        assert_eq!(run("(mean [])", &mut env), "[]");
+        assert_eq!(run("(median [])", &mut env), "[]");
+        assert!(matches!(try_run("(mean [1 a])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn dedupe_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(dedupe [1 1 2 2 1])", &mut env), "[1 2 1]");
+        assert_eq!(run("(dedupe [a b a b])", &mut env), "[a b a b]");
+        assert_eq!(run("(dedupe [[x] [x] x])", &mut env), "[[x] x]");
+        assert_eq!(run("(dedupe [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn filter_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(filter (fun [n] (gt n 2)) [1 2 3 4])", &mut env), "[3 4]");
+        assert_eq!(run("(filter (fun [n] (gt n 9)) [1 2 3 4])", &mut env), "[]");
+        assert_eq!(run("(filter (fun [n] (gt n 0)) [1 2 3 4])", &mut env), "[1 2 3 4]");
+        assert_eq!(run("(filter nil? [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn fold_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(fold add 0 [1 2 3])", &mut env), "6");
+        assert_eq!(run("(fold (fun [acc x] (cons x acc)) [] [a b c])", &mut env), "[c b a]");
+        run("(defn sub-from [acc x] (sub acc x))", &mut env);
+        assert_eq!(run("(fold sub-from 10 [1 2 3])", &mut env), "4");
+        assert_eq!(run("(fold add 5 [])", &mut env), "5");
+    }
+
+    #[test]
+    fn rle_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(rle-encode [a a b c c c])", &mut env), "[[2 a] [1 b] [3 c]]");
+        assert_eq!(run("(rle-encode [[x] [x] x])", &mut env), "[[2 [x]] [1 x]]");
+        assert_eq!(run("(rle-decode [[2 a] [1 b] [3 c]])", &mut env), "[a a b c c c]");
+        assert_eq!(run("(rle-decode (rle-encode [1 1 2 1 1 1]))", &mut env), "[1 1 2 1 1 1]");
+        assert_eq!(run("(rle-encode [])", &mut env), "[]");
+        assert!(matches!(try_run("(rle-decode [[a 1]])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn transpose_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(transpose [[1 2 3] [4 5 6]])", &mut env), "[[1 4] [2 5] [3 6]]");
+        assert_eq!(run("(transpose [[1 2 3] [4] [5 6]])", &mut env), "[[1 4 5]]");
+        assert_eq!(run("(transpose (transpose [[a b] [c d]]))", &mut env), "[[a b] [c d]]");
+        assert_eq!(run("(transpose [])", &mut env), "[]");
+        assert!(matches!(try_run("(transpose [[1] 2])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn reverse_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(reverse [A B C])", &mut env), "[C B A]");
+        assert_eq!(run("(reverse [])", &mut env), "[]");
+        assert_eq!(run("(reverse a)", &mut env), "a");
+        run("(let rev (fun [l] (if (not l) () (append (rev (tail l)) (head l)))))", &mut env);
+        assert_eq!(run("(equal (rev [A B C D E F G]) (reverse [A B C D E F G]))", &mut env), "#t");
+    }
+
+    #[test]
+    fn matrix_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(dot [1 2 3] [4 5 6])", &mut env), "32");
+        assert_eq!(run("(dot [] [])", &mut env), "0");
+        assert!(matches!(try_run("(dot [1 2] [1 2 3])", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert_eq!(run("(mat-mul [[1 2] [3 4]] [[5 6] [7 8]])", &mut env), "[[19 22] [43 50]]");
+        assert_eq!(run("(mat-mul [[1 2 3]] [[1] [2] [3]])", &mut env), "[[14]]");
+        assert!(matches!(try_run("(mat-mul [[1 2] [3 4]] [[1 2]])", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(mat-mul [[1 2] [3]] [[1] [2]])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn member_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(member B [A B C])", &mut env), "#t");
+        assert_eq!(run("(member D [A B C])", &mut env), "[]");
+        assert_eq!(run("(member [1] [[1] 2])", &mut env), "#t");
+        assert_eq!(run("(member A [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn sequence_test() {
+        let mut env = HashMap::new();
+        run("(let c (x y z))", &mut env);
+        assert_eq!(run("c", &mut env), "(x y z)");
+        assert_eq!(run("(list (seq? c) (seq? [x y z]) (seq? x) (seq? \"xyz\"))", &mut env), "[#t #t [] []]");
+        assert_eq!(run("(list (head c) (head [x y z]))", &mut env), "[x x]");
+        assert_eq!(run("(list (tail c) (tail [x y z]))", &mut env), "[[y z] [y z]]");
+        assert_eq!(run("(list (len c) (len [x y z]))", &mut env), "[3 3]");
+        assert_eq!(run("(list (nth 2 c) (nth 2 [x y z]))", &mut env), "[z z]");
+    }
+
+    #[test]
+    fn first_rest_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(list (first [a b c]) (rest [a b c]))", &mut env), "[a [b c]]");
+        assert_eq!(run("(list (first []) (rest []))", &mut env), "[[] []]");
+        assert_eq!(run("(list (first a) (rest a))", &mut env), "[[] []]");
+        assert_eq!(run("(list (first (naturals)) (first (rest (naturals))))", &mut env), "[0 1]");
+    }
+
+    #[test]
+    fn assoc_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(assoc :B [[:A 1] [:B 2]])", &mut env), "[:B 2]");
+        assert_eq!(run("(assoc :C [[:A 1] [:B 2]])", &mut env), "[]");
+        assert_eq!(run("(head (tail (assoc 2 [[1 one] [2 two]])))", &mut env), "two");
+        assert_eq!(run("(assoc :A [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn last_init_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(last [A B C])", &mut env), "C");
+        assert_eq!(run("(init [A B C])", &mut env), "[A B]");
+        assert_eq!(run("(list (last []) (init []))", &mut env), "[[] []]");
+        assert_eq!(run("(list (last [A]) (init [A]))", &mut env), "[A []]");
+    }
+
+    #[test]
+    fn into_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(into [] \"abc\")", &mut env), "[\\a \\b \\c]");
+        assert_eq!(run("(into [x] [[a 1] [b 2]])", &mut env), "[x [a 1] [b 2]]");
+        assert_eq!(run("(into [] (lazy-range 3))", &mut env), "[0 1 2]");
+        assert_eq!(run("(into \"ab\" (into [] \"cd\"))", &mut env), "\"abcd\"");
+        assert!(matches!(try_run("(into [] a)", &mut env), Err(EvalError::InvalidArgument(_))));
+        assert!(matches!(try_run("(into a [])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn flatten_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(flatten [1 [2 [3 4]] 5])", &mut env), "[1 2 3 4 5]");
+        assert_eq!(run("(flatten [1 [] [[]] 2])", &mut env), "[1 2]");
+        assert_eq!(run("(flatten a)", &mut env), "[a]");
+    }
+
+    #[test]
+    fn pluck_test() {
+        let mut env = HashMap::new();
+        run("(let people [[[:name ann] [:age 31]] [[:name bob] [:age 27]] [[:age 5]]])", &mut env);
+        assert_eq!(run("(pluck :name people)", &mut env), "[ann bob []]");
+        assert_eq!(run("(pluck :age people)", &mut env), "[31 27 5]");
+        assert_eq!(run("(pluck :name [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn where_test() {
+        let mut env = HashMap::new();
+        run("(let records [[[:type \"a\"] [:n 1]] [[:type \"b\"] [:n 2]] [[:type \"a\"] [:n 3]]])", &mut env);
+        assert_eq!(run("(pluck :n (where :type \"a\" records))", &mut env), "[1 3]");
+        assert_eq!(run("(where :type \"c\" records)", &mut env), "[]");
+        assert_eq!(run("(where :n 2 records)", &mut env), "[[[:type \"b\"] [:n 2]]]");
+    }
+
+    #[test]
+    fn count_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(count (fun [n] (gt n 2)) [1 2 3 4])", &mut env), "2");
+        assert_eq!(run("(count nil? [[] a [] b])", &mut env), "2");
+        assert_eq!(run("(count a [a b a [a]])", &mut env), "2");
+        assert_eq!(run("(count [a] [a b a [a]])", &mut env), "1");
+        assert_eq!(run("(count-if-not (fun [n] (gt n 2)) [1 2 3 4])", &mut env), "2");
+        assert_eq!(run("(count-if-not nil? [])", &mut env), "0");
+    }
+
+    #[test]
+    fn index_of_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(last-index-of a [a b a c])", &mut env), "2");
+        assert_eq!(run("(index-of a [a b a c])", &mut env), "0");
+        assert_eq!(run("(last-index-of [b] [a [b] c])", &mut env), "1");
+        assert_eq!(run("(last-index-of d [a b c])", &mut env), "[]");
+        assert_eq!(run("(last-index-of \"ab\" \"abcabc\")", &mut env), "3");
+        assert_eq!(run("(last-index-of \"c\" \"éabc\")", &mut env), "3");
+        assert_eq!(run("(index-of \"x\" \"abc\")", &mut env), "[]");
+        assert!(matches!(try_run("(last-index-of a b)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn take_drop_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(take 2 [A B C D])", &mut env), "[A B]");
+        assert_eq!(run("(drop 2 [A B C D])", &mut env), "[C D]");
+        assert_eq!(run("(list (take 0 [A B]) (drop 0 [A B]))", &mut env), "[[] [A B]]");
+        assert_eq!(run("(list (take 5 [A B]) (drop 5 [A B]))", &mut env), "[[A B] []]");
+        assert_eq!(run("(take 2 (drop 3 (naturals)))", &mut env), "[3 4]");
+        assert_eq!(run("(drop 5 (lazy-range 3))", &mut env), "[]");
+        assert_eq!(run("(drop 9223372036854775807 (lazy-range 3))", &mut env), "[]");
+        assert!(matches!(try_run("(drop 9223372036854775807 (naturals))", &mut env), Err(EvalError::LimitExceeded(_))));
+        assert!(matches!(try_run("(drop -1 [A])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn splice_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(splice 1 2 [] [a b c d])", &mut env), "[a d]");
+        assert_eq!(run("(splice 2 0 [x y] [a b c d])", &mut env), "[a b x y c d]");
+        assert_eq!(run("(splice 1 1 [x y] [a b c d])", &mut env), "[a x y c d]");
+        assert_eq!(run("(splice 3 10 [x] [a b c d])", &mut env), "[a b c x]");
+        assert_eq!(run("(splice 9 1 [x] [a b])", &mut env), "[a b x]");
+        assert_eq!(run("(splice -2 1 [] [a b])", &mut env), "[b]");
+        assert!(matches!(try_run("(splice 0 1 x [a b])", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn append_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(append [1 2] [3] [4 5])", &mut env), "[1 2 3 4 5]");
+        assert_eq!(run("(append [1 2] 3)", &mut env), "[1 2 3]");
+        assert_eq!(run("(append [1] 2 [3 4] 5)", &mut env), "[1 2 3 4 5]");
+        assert_eq!(run("(append [] [])", &mut env), "[]");
+    }
+
+    #[test]
+    fn stack_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(push 3 (push 2 (push 1 [])))", &mut env), "[3 2 1]");
+        assert_eq!(run("(peek (push 3 (push 2 (push 1 []))))", &mut env), "3");
+        assert_eq!(run("(peek (pop (push 3 (push 2 (push 1 [])))))", &mut env), "2");
+        assert_eq!(run("(pop (pop (pop [1 2 3])))", &mut env), "[]");
+        assert_eq!(run("(list (peek []) (pop []))", &mut env), "[[] []]");
+    }
+
+    #[test]
+    fn queue_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(enqueue 3 (enqueue 2 (enqueue 1 [])))", &mut env), "[1 2 3]");
+        assert_eq!(run("(front (enqueue 3 (enqueue 2 (enqueue 1 []))))", &mut env), "1");
+        assert_eq!(run("(front (dequeue (enqueue 3 (enqueue 2 (enqueue 1 [])))))", &mut env), "2");
+        assert_eq!(run("(enqueue [b] [[a]])", &mut env), "[[a] [b]]");
+        assert_eq!(run("(list (front []) (dequeue []))", &mut env), "[[] []]");
+    }
+
+    #[test]
+    fn str_concat_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(str-concat \"foo\" \"bar\")", &mut env), "\"foobar\"");
+        assert_eq!(run("(str-concat \"a\" (str-concat \"b\" \"c\") \"d\")", &mut env), "\"abcd\"");
+        assert_eq!(run("(str-concat)", &mut env), "\"\"");
+        assert!(matches!(try_run("(str-concat \"a\" 1)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn str_len_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(str-len \"héllo\")", &mut env), "5");
+        assert_eq!(run("(str-len \"\")", &mut env), "0");
+        assert_eq!(run("(str-len [a b])", &mut env), "0");
+    }
+
+    #[test]
+    fn from_str_test() {
+        let elem: Elem = "(add 1 2)".parse().unwrap();
+        assert_eq!(elem.to_string(), "(add 1 2)");
+        assert_eq!(elem.eval(&mut HashMap::new()).unwrap().to_string(), "3");
+        assert_eq!("[a \"b\"]".parse::<Elem>().unwrap().to_string(), "[a \"b\"]");
+        assert!(matches!("(add 1 2) (add 3 4)".parse::<Elem>(), Err(EvalError::Parse(_))));
+        assert!(matches!("(add 1".parse::<Elem>(), Err(EvalError::Parse(_))));
+        assert_eq!("(a -)".parse::<Elem>().unwrap().to_string(), "(a -)");
+        assert_eq!("(add 1-2 3)".parse::<Elem>().unwrap(), Elem::Call(vec![Elem::Atom(Atom::Symbol("add")), Elem::Atom(Atom::Symbol("1-2")), Elem::Atom(Atom::Number(3))]));
+        assert_eq!("99999999999999999999".parse::<Elem>().unwrap(), Elem::Atom(Atom::Symbol("99999999999999999999")));
+        let source = String::from("(f \"owned\")");
+        let elem: Elem = source.parse().unwrap();
+        drop(source);
+        assert_eq!(elem.to_string(), "(f \"owned\")");
+    }
+
+    #[test]
+    fn display_parse_test() {
+        let elem: Elem = "(list [1 \"two\" \\c] (add -2 3) [[]])".parse().unwrap();
+        let reparsed: Elem = elem.to_string().parse().unwrap();
+        assert_eq!(reparsed, elem);
+        assert_eq!(reparsed.to_string(), elem.to_string());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        let elem: Elem = "(f #x \\c \"two\")".parse().unwrap();
+        assert_eq!(serde_json::to_string(&elem).unwrap(),
+            r#"{"call":[{"atom":{"symbol":"f"}},{"single":{"symbol":"x"}},{"atom":{"char":"c"}},{"atom":{"string":"two"}}]}"#);
+
+        let elem = expr("(list (div -3 6) [1 \"two\" \\c #d] [[]] #|a b|)").unwrap().1.eval(&mut HashMap::new()).unwrap();
+        let json = serde_json::to_string(&elem).unwrap();
+        let back: Elem = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, elem);
+        assert_eq!(back.to_string(), "[-1/2 [1 \"two\" \\c #d] [[]] |a b|]");
+
+        assert_eq!(serde_json::from_str::<Atom>(r#"{"ratio":[4,-8]}"#).unwrap(), Atom::Ratio { num: -1, den: 2 });
+        assert_eq!(serde_json::from_str::<Atom>(r#"{"ratio":[4,2]}"#).unwrap(), Atom::Number(2));
+        assert!(serde_json::from_str::<Atom>(r#"{"ratio":[1,0]}"#).is_err());
+        assert!(serde_json::to_string(&Elem::Lazy(Seq::Range(0, None))).is_err());
+    }
+
+    #[cfg(all(feature = "serde", feature = "bignum"))]
+    #[test]
+    fn serde_bignum_test() {
+        let mut env = HashMap::new();
+        let big = expr("(mul 4611686018427387904 4611686018427387904)").unwrap().1.eval(&mut env).unwrap();
+        let json = serde_json::to_string(&big).unwrap();
+        assert_eq!(json, r#"{"atom":{"bigint":"21267647932558653966460912964485513216"}}"#);
+        assert_eq!(serde_json::from_str::<Elem>(&json).unwrap(), big);
+        assert_eq!(serde_json::from_str::<Atom>(r#"{"bigint":"-12"}"#).unwrap(), Atom::Number(-12));
+        assert!(serde_json::from_str::<Atom>(r#"{"bigint":"1x"}"#).is_err());
+    }
+
+    #[test]
+    fn substring_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(substring \"hello\" 1 3)", &mut env), "\"el\"");
+        assert_eq!(run("(substring \"héllo\" 1 4)", &mut env), "\"éll\"");
+        assert_eq!(run("(substring \"hello\" 2 2)", &mut env), "\"\"");
+        assert_eq!(run("(substring \"hello\" 3 1)", &mut env), "\"\"");
+        assert_eq!(run("(substring \"hello\" -2 99)", &mut env), "\"hello\"");
+        assert_eq!(run("(substring \"hello\" 9 12)", &mut env), "\"\"");
+        assert!(matches!(try_run("(substring abc 0 1)", &mut env), Err(EvalError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn case_test() {
+        let mut env = HashMap::new();
+        assert_eq!(run("(upcase \"abc\")", &mut env), "\"ABC\"");
+        assert_eq!(run("(downcase \"ABC\")", &mut env), "\"abc\"");
+        assert_eq!(run("(upcase \"héllo 1-2!\")", &mut env), "\"HÉLLO 1-2!\"");
+        assert_eq!(run("(downcase \"ÉCOLE 42\")", &mut env), "\"école 42\"");
+        assert!(matches!(try_run("(upcase abc)", &mut env), Err(EvalError::InvalidArgument(_))));
     }
 }
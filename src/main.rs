@@ -1,4 +1,4 @@
-use std::{hash::Hash, collections::HashMap, error::Error};
+use std::{collections::HashMap, error::Error};
 
 use qbscript::eval_and_print;
 
@@ -8,17 +8,17 @@ fn main() -> Result<(),Box<dyn Error>> {
 (let x 7)
 (let double (fun [n] (add n n)))
 (double x)
-(let reverse (fun [l] (if (not l)
-    ()
-    (append (reverse (tail l)) (head l)))))
 (reverse [A B C D E F G])
-(let dec (fun [n] (add n -1)))
 (let iota (fun [n] (if (gt n 0) (append (iota (dec n)) n) n)))
 (iota 10)
     ";
     loop {
-        input = eval_and_print(input,&mut env)?;
-        if input.len() == 0 {
+        let (rest, result) = eval_and_print(input,&mut env)?;
+        if let Err(error) = result {
+            eprintln!("{}",error);
+        }
+        input = rest;
+        if input.is_empty() {
             break;
         }
     }
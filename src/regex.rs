@@ -0,0 +1,409 @@
+//! A small regular expression engine for `regex-match`.
+//!
+//! Supports literals, `.`, `^`, `$`, alternation, capturing and `(?:...)`
+//! groups, bracketed classes, the `\d` `\w` `\s` shorthands and their
+//! negations, and the greedy `*`, `+`, `?` and `{n,m}` quantifiers.
+//!
+//! Patterns compile to a small instruction set run by a Pike VM, which steps
+//! every candidate match forward together instead of backtracking, so the
+//! time taken grows linearly with the text and the stack stays flat.
+
+#[derive(Clone,Debug)]
+enum ClassItem {
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+#[derive(Clone,Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    Group(Box<Node>, Option<usize>),
+    Alt(Vec<Node>),
+    Concat(Vec<Node>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(lo, hi) => lo <= c && c <= hi,
+            ClassItem::Digit(negated) => c.is_ascii_digit() != negated,
+            ClassItem::Word(negated) => (c.is_alphanumeric() || c == '_') != negated,
+            ClassItem::Space(negated) => c.is_whitespace() != negated,
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alt(&mut self) -> Result<Node, String> {
+        let mut alts = vec![self.concat()?];
+        while self.eat('|') {
+            alts.push(self.concat()?);
+        }
+        if alts.len() == 1 {
+            Ok(alts.remove(0))
+        } else {
+            Ok(Node::Alt(alts))
+        }
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.repeat(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+
+    fn repeat(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = self.number().ok_or("expected a count after {")?;
+                let max = if self.eat(',') {
+                    self.number()
+                } else {
+                    Some(min)
+                };
+                if self.peek() != Some('}') {
+                    return Err("unterminated {".to_string());
+                }
+                if max.is_some_and(|max| max < min) {
+                    return Err("repeat counts out of order".to_string());
+                }
+                (min, max)
+            },
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if matches!(atom, Node::Start | Node::End) {
+            return Err("nothing to repeat".to_string());
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    fn escape(&mut self) -> Result<ClassItem, String> {
+        match self.next() {
+            Some('d') => Ok(ClassItem::Digit(false)),
+            Some('D') => Ok(ClassItem::Digit(true)),
+            Some('w') => Ok(ClassItem::Word(false)),
+            Some('W') => Ok(ClassItem::Word(true)),
+            Some('s') => Ok(ClassItem::Space(false)),
+            Some('S') => Ok(ClassItem::Space(true)),
+            Some('n') => Ok(ClassItem::Range('\n', '\n')),
+            Some('t') => Ok(ClassItem::Range('\t', '\t')),
+            Some(c) if !c.is_alphanumeric() => Ok(ClassItem::Range(c, c)),
+            Some(c) => Err(format!("unknown escape \\{}", c)),
+            None => Err("trailing \\".to_string()),
+        }
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                Some(']') if !first => break,
+                Some(c) => c,
+                None => return Err("unterminated [".to_string()),
+            };
+            first = false;
+            let item = if c == '\\' {
+                self.escape()?
+            } else {
+                ClassItem::Range(c, c)
+            };
+            match item {
+                ClassItem::Range(lo, _) if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') => {
+                    self.pos += 1;
+                    let hi = match self.next() {
+                        Some('\\') => match self.escape()? {
+                            ClassItem::Range(hi, _) => hi,
+                            _ => return Err("bad class range".to_string()),
+                        },
+                        Some(hi) => hi,
+                        None => return Err("unterminated [".to_string()),
+                    };
+                    if hi < lo {
+                        return Err("class range out of order".to_string());
+                    }
+                    items.push(ClassItem::Range(lo, hi));
+                },
+                item => items.push(item),
+            }
+        }
+        Ok(Node::Class(items, negated))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some('.') => Ok(Node::Any),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('[') => self.class(),
+            Some('(') => {
+                let index = if self.eat('?') {
+                    if !self.eat(':') {
+                        return Err("unknown group flag".to_string());
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let inner = self.alt()?;
+                if !self.eat(')') {
+                    return Err("unterminated (".to_string());
+                }
+                Ok(Node::Group(Box::new(inner), index))
+            },
+            Some('\\') => match self.escape()? {
+                ClassItem::Range(c, _) => Ok(Node::Char(c)),
+                item => Ok(Node::Class(vec![item], false)),
+            },
+            Some('*' | '+' | '?' | '{') => Err("nothing to repeat".to_string()),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err("unexpected end of pattern".to_string()),
+        }
+    }
+}
+
+/// Patterns that compile to more instructions than this are rejected, which
+/// keeps counted repeats like `a{1000}{1000}` from exhausting memory.
+const PROGRAM_LIMIT: usize = 10_000;
+
+/// An instruction for the matcher. Threads step through these in lockstep,
+/// one input character at a time.
+#[derive(Clone,Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    Save(usize),
+    /// Continues at both targets, preferring the first.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) -> Result<(), String> {
+    if program.len() > PROGRAM_LIMIT {
+        return Err("pattern too large".to_string());
+    }
+    match node {
+        Node::Char(c) => program.push(Inst::Char(*c)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(items, negated) => program.push(Inst::Class(items.clone(), *negated)),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program)?;
+            }
+        },
+        Node::Alt(alts) => {
+            let mut jumps = Vec::new();
+            let (last, rest) = alts.split_last().expect("alternation has at least two branches");
+            for alt in rest {
+                let split = program.len();
+                program.push(Inst::Split(split + 1, 0));
+                compile(alt, program)?;
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                program[split] = Inst::Split(split + 1, program.len());
+            }
+            compile(last, program)?;
+            for jump in jumps {
+                program[jump] = Inst::Jump(program.len());
+            }
+        },
+        Node::Group(inner, None) => compile(inner, program)?,
+        Node::Group(inner, Some(index)) => {
+            program.push(Inst::Save(2 * index));
+            compile(inner, program)?;
+            program.push(Inst::Save(2 * index + 1));
+        },
+        Node::Repeat(inner, min, max) => {
+            for _ in 0..*min {
+                compile(inner, program)?;
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(inner, program)?;
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                },
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(0, 0));
+                        compile(inner, program)?;
+                    }
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, program.len());
+                    }
+                },
+            }
+        },
+    }
+    Ok(())
+}
+
+/// A compiled pattern.
+#[derive(Clone,Debug)]
+pub struct Regex {
+    program: Vec<Inst>,
+    groups: usize,
+}
+
+/// A thread waiting on a consuming instruction, with the capture slots it
+/// has filled in so far.
+struct Thread {
+    pc: usize,
+    slots: Vec<Option<usize>>,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0, groups: 0 };
+        let node = parser.alt()?;
+        if parser.pos < parser.chars.len() {
+            return Err("unmatched )".to_string());
+        }
+        let mut program = vec![Inst::Save(0)];
+        compile(&node, &mut program)?;
+        program.extend([Inst::Save(1), Inst::Match]);
+        if program.len() > PROGRAM_LIMIT {
+            return Err("pattern too large".to_string());
+        }
+        Ok(Regex { program, groups: parser.groups })
+    }
+
+    /// Adds the thread at `pc` to `list`, following jumps, splits, saves and
+    /// anchors until each path reaches an instruction that consumes input.
+    /// Paths are added in priority order, and each instruction only once per
+    /// position, which is what keeps matching linear in the text's length.
+    fn add(&self, list: &mut Vec<Thread>, visited: &mut [usize], pos: usize, len: usize, pc: usize, slots: Vec<Option<usize>>) {
+        let mut stack = vec![(pc, slots)];
+        while let Some((pc, mut slots)) = stack.pop() {
+            if visited[pc] == pos {
+                continue;
+            }
+            visited[pc] = pos;
+            match self.program[pc] {
+                Inst::Jump(to) => stack.push((to, slots)),
+                Inst::Split(first, second) => {
+                    stack.push((second, slots.clone()));
+                    stack.push((first, slots));
+                },
+                Inst::Save(slot) => {
+                    slots[slot] = Some(pos);
+                    stack.push((pc + 1, slots));
+                },
+                Inst::Start => if pos == 0 {
+                    stack.push((pc + 1, slots));
+                },
+                Inst::End => if pos == len {
+                    stack.push((pc + 1, slots));
+                },
+                _ => list.push(Thread { pc, slots }),
+            }
+        }
+    }
+
+    /// Finds the leftmost match in `text`, returning the whole match followed
+    /// by each capturing group, with `None` for groups that didn't take part.
+    ///
+    /// `tick` is called once per character, so the caller can give up on a
+    /// long search by returning an error from it.
+    pub fn captures<E>(&self, text: &str, mut tick: impl FnMut() -> Result<(), E>) -> Result<Option<Vec<Option<String>>>, E> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut visited = vec![usize::MAX; self.program.len()];
+        let mut current = Vec::new();
+        let mut found = None;
+        for pos in 0..=chars.len() {
+            tick()?;
+            if found.is_none() {
+                self.add(&mut current, &mut visited, pos, chars.len(), 0, vec![None; 2 * (self.groups + 1)]);
+            } else if current.is_empty() {
+                break;
+            }
+            let mut next = Vec::new();
+            for Thread { pc, slots } in current.drain(..) {
+                let matches = match &self.program[pc] {
+                    Inst::Char(c) => chars.get(pos) == Some(c),
+                    Inst::Any => chars.get(pos).is_some_and(|&c| c != '\n'),
+                    Inst::Class(items, negated) => chars.get(pos).is_some_and(|&c| items.iter().any(|item| item.matches(c)) != *negated),
+                    _ => {
+                        // Threads after this one have lower priority, so
+                        // they can only find worse matches.
+                        found = Some(slots);
+                        break;
+                    },
+                };
+                if matches {
+                    self.add(&mut next, &mut visited, pos + 1, chars.len(), pc + 1, slots);
+                }
+            }
+            current = next;
+        }
+        Ok(found.map(|slots| slots.chunks(2)
+            .map(|span| match *span {
+                [Some(from), Some(to)] => Some(chars[from..to].iter().collect()),
+                _ => None,
+            })
+            .collect()))
+    }
+}
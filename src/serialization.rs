@@ -0,0 +1,115 @@
+//! Serde support for forms, behind the `serde` feature.
+//!
+//! Each atom and form becomes a single-key map naming its variant, so
+//! `(f "x" 1)` is `{"call":[{"atom":{"symbol":"f"}},{"atom":{"string":"x"}},{"atom":{"number":1}}]}`
+//! in JSON. Lazy sequences and delays can't be serialized, since that would
+//! mean forcing them.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser};
+use std::borrow::Cow;
+
+use super::{Atom, Elem, fraction, intern};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AtomRepr {
+    Symbol(String),
+    String(String),
+    Number(isize),
+    Char(char),
+    Ratio(isize, isize),
+    /// In decimal, since most formats can't hold integers this large.
+    #[cfg(feature = "bignum")]
+    BigInt(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ElemRepr {
+    Atom(AtomRepr),
+    Single(AtomRepr),
+    Call(Vec<ElemRepr>),
+    List(Vec<ElemRepr>),
+}
+
+impl AtomRepr {
+    fn new(atom: &Atom) -> AtomRepr {
+        match atom {
+            Atom::Symbol(name) => AtomRepr::Symbol(name.to_string()),
+            Atom::String(value) => AtomRepr::String(value.to_string()),
+            Atom::Number(value) => AtomRepr::Number(*value),
+            Atom::Char(value) => AtomRepr::Char(*value),
+            Atom::Ratio { num, den } => AtomRepr::Ratio(*num, *den),
+            #[cfg(feature = "bignum")]
+            Atom::BigInt(value) => AtomRepr::BigInt(value.to_string()),
+        }
+    }
+
+    /// The atom this stands for, with ratios reduced and bignums that fit
+    /// turned back into numbers, so a hand-written input can't break the
+    /// invariants arithmetic relies on.
+    fn into_atom<'a>(self) -> Result<Atom<'a>, String> {
+        Ok(match self {
+            AtomRepr::Symbol(name) => Atom::Symbol(intern(&name)),
+            AtomRepr::String(value) => Atom::String(Cow::Owned(value)),
+            AtomRepr::Number(value) => Atom::Number(value),
+            AtomRepr::Char(value) => Atom::Char(value),
+            AtomRepr::Ratio(_, 0) => return Err("ratio with a zero denominator".to_string()),
+            AtomRepr::Ratio(num, den) => fraction(num as i128, den as i128)
+                .ok_or_else(|| format!("ratio {}/{} out of range", num, den))?,
+            #[cfg(feature = "bignum")]
+            AtomRepr::BigInt(digits) => {
+                let value: super::BigInt = digits.parse()?;
+                value.to_isize().map_or(Atom::BigInt(value), Atom::Number)
+            },
+        })
+    }
+}
+
+impl ElemRepr {
+    fn new(elem: &Elem) -> Result<ElemRepr, String> {
+        Ok(match elem {
+            Elem::Atom(atom) => ElemRepr::Atom(AtomRepr::new(atom)),
+            Elem::Single(atom) => ElemRepr::Single(AtomRepr::new(atom)),
+            Elem::Call(items) => ElemRepr::Call(items.iter().map(ElemRepr::new).collect::<Result<_, _>>()?),
+            Elem::List(items) => ElemRepr::List(items.iter().map(ElemRepr::new).collect::<Result<_, _>>()?),
+            Elem::Lazy(_) => return Err("can't serialize a lazy sequence".to_string()),
+            Elem::Delay(_) => return Err("can't serialize a delay".to_string()),
+        })
+    }
+
+    fn into_elem<'a>(self) -> Result<Elem<'a>, String> {
+        Ok(match self {
+            ElemRepr::Atom(atom) => Elem::Atom(atom.into_atom()?),
+            ElemRepr::Single(atom) => Elem::Single(atom.into_atom()?),
+            ElemRepr::Call(items) => Elem::Call(items.into_iter().map(ElemRepr::into_elem).collect::<Result<_, _>>()?),
+            ElemRepr::List(items) => Elem::List(items.into_iter().map(ElemRepr::into_elem).collect::<Result<_, _>>()?),
+        })
+    }
+}
+
+impl Serialize for Atom<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        AtomRepr::new(self).serialize(serializer)
+    }
+}
+
+/// Symbols are interned, so the result borrows nothing from the input.
+impl<'de> Deserialize<'de> for Atom<'_> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        AtomRepr::deserialize(deserializer)?.into_atom().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Elem<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ElemRepr::new(self).map_err(ser::Error::custom)?.serialize(serializer)
+    }
+}
+
+/// Symbols are interned, so the result borrows nothing from the input.
+impl<'de> Deserialize<'de> for Elem<'_> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        ElemRepr::deserialize(deserializer)?.into_elem().map_err(de::Error::custom)
+    }
+}